@@ -0,0 +1,329 @@
+use anyhow::{Result, anyhow};
+use log::{debug, error, info, warn};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, Duration};
+
+use crate::core::config::ApiConfig;
+use crate::core::schedule::parse_in;
+use crate::runtime::Runtime;
+
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+/// How long a client gets to send its request head.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause after a failed accept, e.g. when out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+const DEFAULT_HISTORY_LIMIT: usize = 50;
+const DEFAULT_LOG_LINES: usize = 50;
+
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .get(name)
+            .map(String::as_str)
+            .filter(|v| !v.is_empty())
+    }
+}
+
+struct Response {
+    status: u16,
+    body: Value,
+}
+
+impl Response {
+    fn ok(body: Value) -> Self {
+        Self { status: 200, body }
+    }
+
+    fn error(status: u16, message: impl std::fmt::Display) -> Self {
+        Self {
+            status,
+            body: json!({ "error": message.to_string() }),
+        }
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        out.push(byte);
+                        i += 2;
+                    }
+                    Err(_) => out.push(b'%'),
+                }
+            }
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    let head = loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(anyhow!("Connection closed before request head"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break String::from_utf8_lossy(&buf[..end]).into_owned();
+        }
+        if buf.len() > MAX_REQUEST_SIZE {
+            return Err(anyhow!("Request head too large"));
+        }
+    };
+
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(anyhow!("Malformed request line: {}", request_line));
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query,
+        headers,
+    })
+}
+
+async fn write_response(stream: &mut TcpStream, response: Response) -> Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        _ => "Internal Server Error",
+    };
+    let body = response.body.to_string();
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        reason,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// HTTP JSON API for querying and operating the sessions.
+pub struct ApiServer {
+    runtime: Arc<Runtime>,
+    token: Option<String>,
+    /// Whether routes that change state are served without a token, which is only
+    /// allowed on loopback.
+    open_writes: bool,
+}
+
+impl ApiServer {
+    pub fn new(runtime: Arc<Runtime>, config: &ApiConfig) -> Arc<Self> {
+        Arc::new(Self {
+            runtime,
+            token: config.token.clone(),
+            open_writes: config.address.is_loopback(),
+        })
+    }
+
+    pub async fn serve(self: Arc<Self>, address: IpAddr, port: u16) -> Result<()> {
+        let listener = TcpListener::bind((address, port)).await?;
+        info!("API listening on {}", SocketAddr::new(address, port));
+        if self.token.is_none() && !self.open_writes {
+            warn!(
+                "API_TOKEN is not set, so POST routes are refused on {}",
+                address
+            );
+        }
+        loop {
+            let (mut stream, peer) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    error!("API failed to accept: {}", e);
+                    time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            let server = Arc::clone(&self);
+            tokio::spawn(async move {
+                let response = match time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await
+                {
+                    Ok(Ok(request)) => {
+                        debug!("API {} {} from {}", request.method, request.path, peer);
+                        server.handle(&request).await
+                    }
+                    Ok(Err(e)) => Response::error(400, e),
+                    Err(_) => {
+                        debug!("API request from {} timed out", peer);
+                        return;
+                    }
+                };
+                if let Err(e) = write_response(&mut stream, response).await {
+                    debug!("API response to {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let Some(token) = &self.token else {
+            return true;
+        };
+        request
+            .header("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|value| value.trim() == token)
+    }
+
+    async fn handle(&self, request: &Request) -> Response {
+        if !self.authorized(request) {
+            return Response::error(401, "Missing or invalid token");
+        }
+        if request.method == "POST" && self.token.is_none() && !self.open_writes {
+            return Response::error(403, "Set API_TOKEN to use POST routes off loopback");
+        }
+        let result = match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/alerts") => self.alerts().await,
+            ("GET", "/history") => self.history(request).await,
+            ("GET", "/logs") => self.logs(request),
+            ("GET", "/routes") => self.routes().await,
+            ("POST", "/reload") => self.reload().await,
+            ("POST", "/scale") => self.scale(request).await,
+            _ => return Response::error(404, "Not found"),
+        };
+        result.unwrap_or_else(|e| {
+            error!("API {} {} failed: {}", request.method, request.path, e);
+            Response::error(500, e)
+        })
+    }
+
+    /// `POST /reload`: re-reads the config file and applies what changed.
+    async fn reload(&self) -> Result<Response> {
+        match self.runtime.reload().await {
+            Ok(changes) => Ok(Response::ok(json!({ "changes": changes }))),
+            Err(e) => Ok(Response::error(400, format!("{:#}", e))),
+        }
+    }
+
+    /// `POST /scale?sessions=<n>&account=<name>`: starts or stops sessions of an account.
+    async fn scale(&self, request: &Request) -> Result<Response> {
+        let Some(Ok(sessions)) = request.param("sessions").map(str::parse) else {
+            return Ok(Response::error(400, "Missing or invalid sessions"));
+        };
+        match self.runtime.scale(request.param("account"), sessions).await {
+            Ok(changes) => Ok(Response::ok(json!({ "changes": changes }))),
+            Err(e) => Ok(Response::error(400, format!("{:#}", e))),
+        }
+    }
+
+    /// `GET /alerts`: sessions that gave up reconnecting and wait for someone to connect
+    /// them again.
+    async fn alerts(&self) -> Result<Response> {
+        let stats = self.runtime.manager().get_all_stats().await;
+        let alerts: Vec<_> = stats
+            .iter()
+            .filter_map(|(interface, info)| {
+                let reason = info.gave_up.as_ref()?;
+                Some(json!({
+                    "interface": interface,
+                    "reason": reason.to_string(),
+                }))
+            })
+            .collect();
+        Ok(Response::ok(json!(alerts)))
+    }
+
+    /// `GET /routes`: the interface rules and default routes currently in the kernel.
+    async fn routes(&self) -> Result<Response> {
+        let routing = self.runtime.manager().routes().routing();
+        Ok(Response::ok(json!({
+            "rules": routing.rules().await?,
+            "default_routes": routing.default_routes().await?,
+        })))
+    }
+
+    /// `GET /logs?interface=<name>&lines=<n>`: the last captured pppd output of a session.
+    fn logs(&self, request: &Request) -> Result<Response> {
+        let Some(interface) = request.param("interface") else {
+            return Ok(Response::error(400, "Missing interface"));
+        };
+        let lines = match request.param("lines").map(str::parse) {
+            Some(Ok(lines)) => lines,
+            Some(Err(_)) => return Ok(Response::error(400, "Invalid lines")),
+            None => DEFAULT_LOG_LINES,
+        };
+        match self.runtime.manager().logs().tail(interface, lines) {
+            Some(lines) => Ok(Response::ok(json!({
+                "interface": interface,
+                "lines": lines,
+            }))),
+            None => Ok(Response::error(404, format!("No logs for {}", interface))),
+        }
+    }
+
+    /// `GET /history?ip=<ip>&at=<time>&limit=<n>`: leases matching an IP and/or active at a time.
+    async fn history(&self, request: &Request) -> Result<Response> {
+        let at = match request.param("at") {
+            Some(at) => match parse_in(at, self.runtime.manager().timezone()) {
+                Ok(at) => Some(at),
+                Err(e) => return Ok(Response::error(400, e)),
+            },
+            None => None,
+        };
+        let limit = match request.param("limit").map(str::parse) {
+            Some(Ok(limit)) => limit,
+            Some(Err(_)) => return Ok(Response::error(400, "Invalid limit")),
+            None => DEFAULT_HISTORY_LIMIT,
+        };
+        let leases = self
+            .runtime
+            .manager()
+            .history()
+            .query(request.param("ip"), at, limit)
+            .await?;
+        Ok(Response::ok(serde_json::to_value(leases)?))
+    }
+}
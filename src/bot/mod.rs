@@ -1,14 +1,23 @@
-use crate::pppoe::manager::PPPoEManager;
+use crate::core::schedule::{format_in, parse_in};
+use crate::pppoe::manager::{Alert, PPPoEManager};
+use crate::runtime::Runtime;
 use anyhow::{Error, Result};
+use log::{error, warn};
 use poise::serenity_prelude as serenity;
+use std::collections::BTreeSet;
 use std::sync::Arc;
+use tokio::sync::broadcast;
 
 pub struct Data {
     pub manager: Arc<PPPoEManager>,
+    pub runtime: Arc<Runtime>,
 }
 
 pub type Context<'a> = poise::Context<'a, Data, Error>;
 
+/// Room for log lines in a reply, within Discord's 2000 characters per message.
+const MAX_LOG_REPLY: usize = 1900;
+
 /// Autocomplete function for interface names
 async fn autocomplete_interface<'a>(ctx: Context<'a>, partial: &'a str) -> Vec<String> {
     let manager = &ctx.data().manager;
@@ -33,6 +42,9 @@ pub async fn status(ctx: Context<'_>) -> Result<()> {
 
     let mut all_healthy = true;
     let mut any_connected = false;
+    // Only worth showing when sessions are split across uplinks.
+    let uplinks: BTreeSet<&str> = stats.values().map(|info| info.uplink.as_str()).collect();
+    let show_uplink = uplinks.len() > 1;
 
     for (interface, info) in stats {
         let status_emoji = if info.local_ip.is_some() {
@@ -43,6 +55,8 @@ pub async fn status(ctx: Context<'_>) -> Result<()> {
                 all_healthy = false;
                 "⚠️"
             }
+        } else if info.gave_up.is_some() {
+            "⛔"
         } else {
             "🔴"
         };
@@ -50,6 +64,9 @@ pub async fn status(ctx: Context<'_>) -> Result<()> {
         let mut value = String::new();
         if let Some(ip) = info.local_ip {
             value.push_str(&format!("**IP:** {}\n", ip));
+            if let Some(remote_ip) = info.lease.remote_ip {
+                value.push_str(&format!("**Peer:** {}\n", remote_ip));
+            }
             if let Some(connected_at) = info.connected_at {
                 let duration = chrono::Utc::now() - connected_at;
                 let hours = duration.num_hours();
@@ -60,6 +77,10 @@ pub async fn status(ctx: Context<'_>) -> Result<()> {
                     hours, minutes, seconds
                 ));
             }
+            value.push_str(&format!("**Connections:** {}\n", info.active_connections));
+            if info.lease_attempts > 1 {
+                value.push_str(&format!("**Lease Attempts:** {}\n", info.lease_attempts));
+            }
             if !info.is_healthy {
                 value.push_str(&format!("**Failures:** {}\n", info.consecutive_failures));
             }
@@ -70,11 +91,51 @@ pub async fn status(ctx: Context<'_>) -> Result<()> {
                     since_check.num_seconds()
                 ));
             }
+        } else if let Some(reason) = &info.gave_up {
+            value.push_str(&format!("Gave up reconnecting: {}\n", reason));
+        } else if info.pending_attempts > 0 {
+            value.push_str(&format!(
+                "Rejected {} IP(s), reconnecting\n",
+                info.pending_attempts
+            ));
+        } else if let Some(reason) = &info.lease.termination_reason {
+            value.push_str(&format!("Disconnected: {}\n", reason));
         } else {
-            value.push_str("Disconnected");
+            value.push_str("Disconnected\n");
+        }
+        if info.rejected_ips > 0 {
+            value.push_str(&format!("**Rejected IPs:** {}\n", info.rejected_ips));
+        }
+        if let Some(rotation) = &info.last_rotation {
+            let since = chrono::Utc::now() - rotation.finished_at;
+            value.push_str(&format!(
+                "**Last Rotation:** {}m ago, {} attempt(s){}\n",
+                since.num_minutes(),
+                rotation.attempts,
+                if rotation.changed() {
+                    ""
+                } else {
+                    ", IP unchanged"
+                }
+            ));
+        }
+        if let Some(next_rotation) = info.next_rotation_at {
+            let until = next_rotation - chrono::Utc::now();
+            value.push_str(&format!(
+                "**Next Rotation:** {} (in {}m)\n",
+                format_in(next_rotation, manager.timezone(), "%m-%d %H:%M"),
+                until.num_minutes().max(0)
+            ));
         }
 
-        embed = embed.field(format!("{} {}", status_emoji, interface), value, false);
+        let mut title = format!("{} {}", status_emoji, interface);
+        if !info.account.is_empty() {
+            title.push_str(&format!(" · {}", info.account));
+        }
+        if show_uplink {
+            title.push_str(&format!(" via {}", info.uplink));
+        }
+        embed = embed.field(title, value, false);
     }
 
     if all_healthy && any_connected {
@@ -98,9 +159,15 @@ pub async fn reconnect(
     interface: String,
 ) -> Result<()> {
     let manager = &ctx.data().manager;
+    // Waiting for a new lease takes longer than Discord's reply deadline.
+    ctx.defer().await?;
     match manager.reconnect_client(&interface).await {
-        Ok(_) => {
-            ctx.say(format!("Reconnecting {}...", interface)).await?;
+        Ok(outcome) if outcome.changed() => {
+            ctx.say(format!("✅ Reconnected {}", outcome)).await?;
+        }
+        Ok(outcome) => {
+            ctx.say(format!("⚠️ Reconnected without a new IP: {}", outcome))
+                .await?;
         }
         Err(e) => {
             ctx.say(format!("Failed to reconnect {}: {}", interface, e))
@@ -175,10 +242,143 @@ pub async fn healthcheck(
     Ok(())
 }
 
+/// Show the last lines pppd printed for a PPPoE interface
+#[poise::command(slash_command)]
+pub async fn logs(
+    ctx: Context<'_>,
+    #[description = "Interface name (e.g., ppp0)"]
+    #[autocomplete = "autocomplete_interface"]
+    interface: String,
+    #[description = "Number of lines (default 20)"] lines: Option<usize>,
+) -> Result<()> {
+    let manager = &ctx.data().manager;
+    let Some(lines) = manager.logs().tail(&interface, lines.unwrap_or(20)) else {
+        ctx.say(format!("No logs for {}", interface)).await?;
+        return Ok(());
+    };
+    if lines.is_empty() {
+        ctx.say(format!("{} has not logged anything yet", interface))
+            .await?;
+        return Ok(());
+    }
+
+    // Drop the oldest lines until the reply fits in a Discord message.
+    let mut text = String::new();
+    for line in lines.iter().rev() {
+        if text.len() + line.len() + 1 > MAX_LOG_REPLY {
+            break;
+        }
+        text.insert_str(0, &format!("{}\n", line));
+    }
+    ctx.say(format!("**{}**\n```\n{}```", interface, text))
+        .await?;
+    Ok(())
+}
+
+/// Look up which session held an IP, or which IPs were in use at a time
+#[poise::command(slash_command)]
+pub async fn history(
+    ctx: Context<'_>,
+    #[description = "IP address"] ip: Option<String>,
+    #[description = "Time, e.g. 2024-05-01 13:45 (schedule timezone) or RFC 3339"] at: Option<
+        String,
+    >,
+) -> Result<()> {
+    let manager = &ctx.data().manager;
+    let tz = manager.timezone();
+    let at = match at.as_deref().map(|at| parse_in(at, tz)).transpose() {
+        Ok(at) => at,
+        Err(e) => {
+            ctx.say(format!("Invalid time: {}", e)).await?;
+            return Ok(());
+        }
+    };
+    let leases = manager.history().query(ip.as_deref(), at, 10).await?;
+    if leases.is_empty() {
+        ctx.say("No matching leases").await?;
+        return Ok(());
+    }
+
+    let mut embed = serenity::CreateEmbed::default()
+        .title("IP History")
+        .timestamp(chrono::Utc::now());
+    for lease in leases {
+        let until = lease
+            .disconnected_at
+            .map(|end| format_in(end, tz, "%Y-%m-%d %H:%M:%S"))
+            .unwrap_or_else(|| "now".to_string());
+        let value = format!(
+            "**From:** {}\n**Until:** {}\n**Sent:** {} MB, **Received:** {} MB\n",
+            format_in(lease.connected_at, tz, "%Y-%m-%d %H:%M:%S"),
+            until,
+            lease.bytes_sent / 1_000_000,
+            lease.bytes_received / 1_000_000
+        );
+        embed = embed.field(format!("{} {}", lease.interface, lease.ip), value, false);
+    }
+    ctx.send(poise::CreateReply::default().embed(embed)).await?;
+    Ok(())
+}
+
+/// Start or stop sessions of an account
+#[poise::command(slash_command)]
+pub async fn scale(
+    ctx: Context<'_>,
+    #[description = "Number of sessions"] sessions: u16,
+    #[description = "Account name, needed when there are several"] account: Option<String>,
+) -> Result<()> {
+    let runtime = &ctx.data().runtime;
+    ctx.defer().await?;
+    match runtime.scale(account.as_deref(), sessions).await {
+        Ok(changes) if changes.is_empty() => {
+            ctx.say("Nothing to change").await?;
+        }
+        Ok(changes) => {
+            ctx.say(format!("✅ Scaled: {}", changes.join("; ")))
+                .await?;
+        }
+        Err(e) => {
+            ctx.say(format!("Failed to scale: {:#}", e)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Posts every alert the manager raises to `channel` until the manager goes away.
+async fn post_alerts(
+    http: Arc<serenity::Http>,
+    channel: serenity::ChannelId,
+    mut alerts: broadcast::Receiver<Alert>,
+) {
+    loop {
+        let alert = match alerts.recv().await {
+            Ok(alert) => alert,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("Dropped {} alerts for the Discord channel", missed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let embed = serenity::CreateEmbed::default()
+            .title(format!("⛔ {} gave up reconnecting", alert.interface))
+            .description(format!(
+                "{}\nUse `/connect` once that is fixed.",
+                alert.reason
+            ))
+            .color(0xFF0000)
+            .timestamp(alert.at);
+        let message = serenity::CreateMessage::new().embed(embed);
+        if let Err(e) = channel.send_message(&http, message).await {
+            error!("Failed to post alert to Discord: {}", e);
+        }
+    }
+}
+
 pub async fn start_bot(
     token: String,
     guild_id: Option<u64>,
-    manager: Arc<PPPoEManager>,
+    alert_channel: Option<u64>,
+    runtime: Arc<Runtime>,
 ) -> Result<()> {
     let intents = serenity::GatewayIntents::non_privileged();
 
@@ -190,6 +390,9 @@ pub async fn start_bot(
                 disconnect(),
                 connect(),
                 healthcheck(),
+                history(),
+                logs(),
+                scale(),
             ],
             ..Default::default()
         })
@@ -205,7 +408,17 @@ pub async fn start_bot(
                 } else {
                     poise::builtins::register_globally(ctx, &framework.options().commands).await?;
                 }
-                Ok(Data { manager })
+                if let Some(channel) = alert_channel {
+                    tokio::spawn(post_alerts(
+                        Arc::clone(&ctx.http),
+                        serenity::ChannelId::new(channel),
+                        runtime.manager().alerts(),
+                    ));
+                }
+                Ok(Data {
+                    manager: Arc::clone(runtime.manager()),
+                    runtime,
+                })
             })
         })
         .build();
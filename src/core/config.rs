@@ -1,152 +1,711 @@
 use anyhow::{Context, Result, anyhow};
-use chrono::{Local, Timelike};
-use log::debug;
+use ipnet::IpNet;
+use std::collections::BTreeMap;
 use std::env;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::core::config_file::{FileConfig, parse_cidr};
+use crate::core::schedule::{RotationSchedule, ScheduleTimezone};
+
+/// Each session's tun gets the subnet `192.168.{101 + i}.0/24`, which runs out at 255.
+pub const MAX_SESSION_COUNT: u16 = 155;
+
+/// The proxy for the WAN listens here and the one for session `i` on the port `i + 1` above.
+pub const PROXY_PORT_BASE: u16 = 8080;
+/// gost's tun service for `tunN` listens on this port plus `N`.
+pub const TUN_PORT_BASE: u16 = 8880;
+pub const GOST_API_PORT: u16 = 18080;
+pub const GOST_METRICS_PORT: u16 = 18081;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RotationMode {
+    /// Disconnect every session, wait, then reconnect them all.
+    All,
+    /// Rotate a batch of sessions at a time while the rest keep serving.
+    Rolling,
+}
+
+impl FromStr for RotationMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "all" => Ok(RotationMode::All),
+            "rolling" => Ok(RotationMode::Rolling),
+            _ => Err(anyhow!(
+                "Invalid rotation mode: {}. Must be all or rolling",
+                s
+            )),
+        }
+    }
+}
+
+/// What dials the sessions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PppoeBackend {
+    /// `pppd` with the `pppoe` pty helper.
+    Pppd,
+    /// The built-in client in [`crate::pppoe::native`].
+    Native,
+}
+
+impl FromStr for PppoeBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pppd" => Ok(PppoeBackend::Pppd),
+            "native" => Ok(PppoeBackend::Native),
+            _ => Err(anyhow!(
+                "Invalid PPPoE backend: {}. Must be pppd or native",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for PppoeBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PppoeBackend::Pppd => write!(f, "pppd"),
+            PppoeBackend::Native => write!(f, "native"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpVerdict {
+    Accept,
+    Denied,
+    NotPreferred,
+}
+
+impl std::fmt::Display for IpVerdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IpVerdict::Accept => write!(f, "accepted"),
+            IpVerdict::Denied => write!(f, "in a denied range"),
+            IpVerdict::NotPreferred => write!(f, "not in a preferred range"),
+        }
+    }
+}
+
+/// Address ranges an acquired IP is checked against.
+///
+/// Denied IPs are always reconnected; IPs outside the preferred ranges are rerolled
+/// up to `max_attempts` times and then kept.
+#[derive(Debug, Clone, Default)]
+pub struct IpFilterConfig {
+    pub deny: Vec<IpNet>,
+    pub prefer: Vec<IpNet>,
+    pub max_attempts: u32,
+}
+
+impl IpFilterConfig {
+    pub fn verdict(&self, ip: &str) -> IpVerdict {
+        let Ok(ip) = ip.parse::<IpAddr>() else {
+            return IpVerdict::Accept;
+        };
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            IpVerdict::Denied
+        } else if !self.prefer.is_empty() && !self.prefer.iter().any(|net| net.contains(&ip)) {
+            IpVerdict::NotPreferred
+        } else {
+            IpVerdict::Accept
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct IpRotationConfig {
-    pub rotation_time: String,
+    pub schedule: RotationSchedule,
+    pub session_schedules: BTreeMap<String, RotationSchedule>,
+    pub timezone: ScheduleTimezone,
     pub wait_seconds: u32,
+    pub mode: RotationMode,
+    pub batch_size: usize,
+    pub reconnect_timeout_secs: u64,
+    pub max_rerolls: u32,
+    pub ip_filter: IpFilterConfig,
     pub health_check_enabled: bool,
     pub health_check_interval_secs: u64,
     pub health_check_failure_threshold: u32,
     pub health_check_target: String,
 }
 
-#[derive(Debug, Clone)]
-pub struct AppConfig {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BalanceStrategy {
+    RoundRobin,
+    LeastConnections,
+    LeastBandwidth,
+}
+
+impl FromStr for BalanceStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "round_robin" => Ok(BalanceStrategy::RoundRobin),
+            "least_connections" => Ok(BalanceStrategy::LeastConnections),
+            "least_bandwidth" => Ok(BalanceStrategy::LeastBandwidth),
+            _ => Err(anyhow!(
+                "Invalid balance strategy: {}. Must be round_robin, least_connections or least_bandwidth",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StickyKey {
+    /// The `<key>` in a `sticky-<key>` auth username.
+    Username,
+    SourceIp,
+    /// An HTTP request header; SOCKS5 clients are not sticky with this source.
+    Header(String),
+}
+
+impl FromStr for StickyKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "username" => Ok(StickyKey::Username),
+            "source_ip" => Ok(StickyKey::SourceIp),
+            _ => match s.strip_prefix("header:") {
+                Some(name) if !name.is_empty() => Ok(StickyKey::Header(name.to_string())),
+                _ => Err(anyhow!(
+                    "Invalid sticky key: {}. Must be username, source_ip or header:<name>",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StickyPolicy {
+    /// Drop the mapping when the session's IP changes.
+    Invalidate,
+    /// Stay on the session even after its IP changes.
+    Keep,
+}
+
+impl FromStr for StickyPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "invalidate" => Ok(StickyPolicy::Invalidate),
+            "keep" => Ok(StickyPolicy::Keep),
+            _ => Err(anyhow!(
+                "Invalid sticky IP change policy: {}. Must be invalidate or keep",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyConfig {
+    pub selector_port: u16,
+    pub balancer_port: u16,
+    pub balance_strategy: BalanceStrategy,
+    pub sticky_key: StickyKey,
+    pub sticky_ttl_secs: u64,
+    pub sticky_on_ip_change: StickyPolicy,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoutingConfig {
+    /// Where the allocator starts looking for free routing tables and `ip rule` priorities.
+    pub table_base: u32,
+    pub priority_base: u32,
+    /// How often installed rules and routes are checked against the kernel, 0 disables.
+    pub reconcile_interval_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiConfig {
+    /// 0 disables the API.
+    pub port: u16,
+    /// Where the API listens; loopback unless exposed on purpose.
+    pub address: IpAddr,
+    /// Required as `Authorization: Bearer <token>` when set.
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionLogConfig {
+    /// Lines of pppd output kept in memory for each session.
+    pub lines: usize,
+    /// Where to also write each session's output as `<interface>.log`; memory only if unset.
+    pub dir: Option<String>,
+    /// Size at which a log file is rotated.
+    pub max_bytes: u64,
+    /// Rotated files kept besides the current one.
+    pub max_files: u32,
+}
+
+/// An ISP account and how many sessions to dial with it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountConfig {
+    /// Label shown with the account's sessions; the username unless set.
+    pub name: String,
+    pub username: String,
+    pub password: String,
+    pub sessions: u16,
+    /// NICs or VLANs the sessions dial over, taking turns; the WAN interface if empty.
+    pub uplinks: Vec<String>,
+}
+
+/// One session to dial and how.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionConfig {
+    pub interface: String,
+    pub account: String,
     pub username: String,
     pub password: String,
+    /// The ethernet device pppd sends discovery over.
+    pub uplink: String,
+    pub backend: PppoeBackend,
+}
+
+#[derive(Debug, Clone)]
+pub struct AppConfig {
+    /// Sessions are numbered across accounts in order: the first account's come first.
+    pub accounts: Vec<AccountConfig>,
+    /// Total sessions over all accounts.
     pub session_count: u16,
     pub ip_rotation: IpRotationConfig,
+    pub proxy: ProxyConfig,
+    pub routing: RoutingConfig,
+    pub api: ApiConfig,
+    pub history_path: String,
+    pub session_log: SessionLogConfig,
+    /// `RUST_LOG`-style filter for ppproxy's own logs.
+    pub log_filter: String,
+    /// Log level of gost.
     pub logger_level: String,
     pub discord_token: String,
     pub discord_guild_id: Option<u64>,
+    /// Channel the bot posts alerts to, such as a session giving up.
+    pub discord_alert_channel: Option<u64>,
     pub gateway: String,
+    /// Where clients reach the proxies and direct traffic leaves; the default uplink.
+    pub wan_interface: String,
+    /// Uplinks of single sessions, overriding their account's.
+    pub session_uplinks: BTreeMap<String, String>,
+    pub pppoe_backend: PppoeBackend,
+}
+
+/// Where the structured config file is read from: `CONFIG_FILE`, or `config.toml` if it exists.
+fn config_file() -> Option<PathBuf> {
+    match env_value("CONFIG_FILE") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => Some(PathBuf::from("config.toml")).filter(|path| path.exists()),
+    }
+}
+
+/// An env var, treating an empty value as unset.
+fn env_value(var: &str) -> Option<String> {
+    env::var(var).ok().filter(|value| !value.is_empty())
+}
+
+/// Replaces a config file value with `var` if that is set.
+fn env_override<T: FromStr>(var: &str, target: &mut Option<T>) -> Result<()>
+where
+    T::Err: Into<anyhow::Error>,
+{
+    if let Some(value) = env_value(var) {
+        let value = value
+            .parse()
+            .map_err(Into::into)
+            .with_context(|| format!("Invalid {}", var))?;
+        *target = Some(value);
+    }
+    Ok(())
+}
+
+fn required<T>(value: Option<T>, key: &str, var: &str) -> Result<T> {
+    value.ok_or_else(|| anyhow!("{} ({}) not set", key, var))
 }
 
 impl AppConfig {
+    /// Reads the config file and `.env`, with env vars overriding the file.
     pub fn load() -> Result<Self> {
         dotenvy::dotenv().unwrap_or_default();
+        Self::from_sources()
+    }
 
-        let username = env::var("PPPOE_USERNAME").context("PPPOE_USERNAME not set")?;
-        let password = env::var("PPPOE_PASSWORD").context("PPPOE_PASSWORD not set")?;
-
-        let session_count: u16 = env::var("PPPOE_SESSION_COUNT")
-            .unwrap_or_else(|_| "1".to_string())
-            .parse()
-            .context("Invalid PPPOE_SESSION_COUNT")?;
+    /// Reads the config again, with values from `.env` replacing those loaded at startup.
+    pub fn reload() -> Result<Self> {
+        dotenvy::dotenv_override().unwrap_or_default();
+        Self::from_sources()
+    }
 
-        if session_count > 7 {
-            return Err(anyhow!("PPPOE_SESSION_COUNT cannot exceed 7"));
+    /// Every session with the account it logs in with, numbered across accounts in order.
+    pub fn sessions(&self) -> Vec<SessionConfig> {
+        let mut sessions = Vec::new();
+        for account in &self.accounts {
+            for i in 0..usize::from(account.sessions) {
+                let interface = format!("ppp{}", sessions.len());
+                let uplink = match self.session_uplinks.get(&interface) {
+                    Some(uplink) => uplink.clone(),
+                    None if account.uplinks.is_empty() => self.wan_interface.clone(),
+                    None => account.uplinks[i % account.uplinks.len()].clone(),
+                };
+                sessions.push(SessionConfig {
+                    interface,
+                    account: account.name.clone(),
+                    username: account.username.clone(),
+                    password: account.password.clone(),
+                    uplink,
+                    backend: self.pppoe_backend,
+                });
+            }
         }
+        sessions
+    }
 
-        let discord_token = env::var("DISCORD_TOKEN").context("DISCORD_TOKEN not set")?;
-        let discord_guild_id = env::var("DISCORD_GUILD_ID")
-            .ok()
-            .and_then(|id| id.parse().ok());
+    fn from_sources() -> Result<Self> {
+        let mut file = match config_file() {
+            Some(path) => FileConfig::read(&path)?,
+            None => FileConfig::default(),
+        };
+        apply_env(&mut file)?;
+        Self::from_file(file)
+    }
 
-        let logger_level = env::var("GOST_LOG_LEVEL").unwrap_or_else(|_| "warn".to_string());
+    /// Fills in defaults and checks what the types alone do not.
+    fn from_file(file: FileConfig) -> Result<Self> {
+        let FileConfig {
+            gateway,
+            wan_interface,
+            session_uplinks,
+            pppoe_backend,
+            history_path,
+            log,
+            session_log,
+            pppoe,
+            accounts,
+            rotation,
+            ip_filter,
+            health_check,
+            proxy,
+            gost,
+            routing,
+            api,
+            discord,
+        } = file;
 
-        let rotation_time = env::var("IP_ROTATION_TIME").context("IP_ROTATION_TIME not set")?;
-        let wait_seconds_str =
-            env::var("IP_ROTATION_WAIT_SECONDS").context("IP_ROTATION_WAIT_SECONDS not set")?;
+        let accounts = if accounts.is_empty() {
+            let username = required(pppoe.username, "pppoe.username", "PPPOE_USERNAME")?;
+            vec![AccountConfig {
+                name: username.clone(),
+                username,
+                password: required(pppoe.password, "pppoe.password", "PPPOE_PASSWORD")?,
+                sessions: pppoe.session_count.unwrap_or(1),
+                uplinks: pppoe.uplinks.unwrap_or_default(),
+            }]
+        } else {
+            if pppoe.username.is_some()
+                || pppoe.password.is_some()
+                || pppoe.session_count.is_some()
+                || pppoe.uplinks.is_some()
+            {
+                return Err(anyhow!(
+                    "Set either accounts or pppoe (PPPOE_USERNAME, PPPOE_PASSWORD, PPPOE_SESSION_COUNT, PPPOE_UPLINKS), not both"
+                ));
+            }
+            let mut names = Vec::new();
+            for (i, account) in accounts.iter().enumerate() {
+                let name = account.name.as_ref().unwrap_or(&account.username);
+                if names.contains(&name) {
+                    return Err(anyhow!(
+                        "Invalid accounts[{}].name: {} is used twice",
+                        i,
+                        name
+                    ));
+                }
+                names.push(name);
+            }
+            accounts
+                .into_iter()
+                .map(|account| AccountConfig {
+                    name: account.name.unwrap_or_else(|| account.username.clone()),
+                    username: account.username,
+                    password: account.password,
+                    sessions: account.sessions,
+                    uplinks: account.uplinks,
+                })
+                .collect()
+        };
+        let session_count = accounts
+            .iter()
+            .map(|account| u32::from(account.sessions))
+            .sum::<u32>();
+        if session_count > u32::from(MAX_SESSION_COUNT) {
+            return Err(anyhow!(
+                "Invalid session count: {} sessions over all accounts, cannot exceed {}",
+                session_count,
+                MAX_SESSION_COUNT
+            ));
+        }
+        let session_count = session_count as u16;
 
-        if !is_valid_time_format(&rotation_time) && rotation_time.parse::<u32>().is_err() {
+        let session_uplinks = session_uplinks.unwrap_or_default();
+        if let Some(interface) = session_uplinks.keys().find(|interface| {
+            interface
+                .strip_prefix("ppp")
+                .and_then(|index| index.parse::<u16>().ok())
+                .is_none_or(|index| index >= session_count)
+        }) {
             return Err(anyhow!(
-                "Invalid IP_ROTATION_TIME: {}. Must be in HH:MM format or a positive integer representing minutes",
-                rotation_time
+                "Invalid session_uplinks.{} (PPPOE_SESSION_UPLINKS): No such session",
+                interface
             ));
         }
 
-        let wait_seconds = wait_seconds_str
-            .parse::<u32>()
-            .context("Invalid IP_ROTATION_WAIT_SECONDS: Must be a non-negative integer")?;
+        let batch_size = rotation.batch_size.unwrap_or(1);
+        if batch_size == 0 {
+            return Err(anyhow!(
+                "Invalid rotation.batch_size (IP_ROTATION_BATCH_SIZE): Must be a positive integer"
+            ));
+        }
 
-        let health_check_enabled = env::var("HEALTH_CHECK_ENABLED")
-            .unwrap_or_else(|_| "true".to_string())
-            .parse()
-            .unwrap_or(true);
+        let ip_rotation = IpRotationConfig {
+            schedule: required(rotation.schedule, "rotation.schedule", "IP_ROTATION_TIME")?,
+            session_schedules: rotation.sessions.unwrap_or_default(),
+            timezone: rotation.timezone.unwrap_or_default(),
+            wait_seconds: required(
+                rotation.wait_seconds,
+                "rotation.wait_seconds",
+                "IP_ROTATION_WAIT_SECONDS",
+            )?,
+            mode: rotation.mode.unwrap_or(RotationMode::All),
+            batch_size,
+            reconnect_timeout_secs: rotation.timeout.unwrap_or(60),
+            max_rerolls: rotation.max_rerolls.unwrap_or(3),
+            ip_filter: IpFilterConfig {
+                deny: ip_filter.deny.unwrap_or_default(),
+                prefer: ip_filter.prefer.unwrap_or_default(),
+                max_attempts: ip_filter.max_attempts.unwrap_or(5),
+            },
+            health_check_enabled: health_check.enabled.unwrap_or(true),
+            health_check_interval_secs: health_check.interval.unwrap_or(30),
+            health_check_failure_threshold: health_check.threshold.unwrap_or(3),
+            health_check_target: health_check.target.unwrap_or_else(|| "8.8.8.8".to_string()),
+        };
 
-        let health_check_interval_secs = env::var("HEALTH_CHECK_INTERVAL")
-            .unwrap_or_else(|_| "30".to_string())
-            .parse()
-            .unwrap_or(30);
+        let proxy = ProxyConfig {
+            selector_port: proxy.selector_port.unwrap_or(8000),
+            balancer_port: proxy.balancer_port.unwrap_or(8001),
+            balance_strategy: proxy
+                .balance_strategy
+                .unwrap_or(BalanceStrategy::RoundRobin),
+            sticky_key: proxy.sticky_key.unwrap_or(StickyKey::Username),
+            sticky_ttl_secs: proxy.sticky_ttl.unwrap_or(1800),
+            sticky_on_ip_change: proxy
+                .sticky_on_ip_change
+                .unwrap_or(StickyPolicy::Invalidate),
+        };
 
-        let health_check_failure_threshold = env::var("HEALTH_CHECK_THRESHOLD")
-            .unwrap_or_else(|_| "3".to_string())
-            .parse()
-            .unwrap_or(3);
+        let config = Self {
+            accounts,
+            session_count,
+            ip_rotation,
+            proxy,
+            routing: RoutingConfig {
+                table_base: routing.table_base.unwrap_or(100),
+                priority_base: routing.priority_base.unwrap_or(100),
+                reconcile_interval_secs: routing.reconcile_interval.unwrap_or(30),
+            },
+            api: ApiConfig {
+                port: api.port.unwrap_or(0),
+                address: api
+                    .address
+                    .unwrap_or(IpAddr::V4(std::net::Ipv4Addr::LOCALHOST)),
+                token: api.token.filter(|token| !token.is_empty()),
+            },
+            history_path: history_path.unwrap_or_else(|| "data/ip_history.jsonl".to_string()),
+            session_log: SessionLogConfig {
+                lines: session_log.lines.unwrap_or(200),
+                dir: session_log.dir.filter(|dir| !dir.is_empty()),
+                max_bytes: session_log.max_bytes.unwrap_or(1024 * 1024),
+                max_files: session_log.max_files.unwrap_or(3),
+            },
+            log_filter: log.unwrap_or_else(|| "info".to_string()),
+            logger_level: gost.log_level.unwrap_or_else(|| "warn".to_string()),
+            discord_token: required(discord.token, "discord.token", "DISCORD_TOKEN")?,
+            discord_guild_id: discord.guild_id,
+            discord_alert_channel: discord.alert_channel,
+            gateway: required(gateway, "gateway", "GATEWAY")?,
+            wan_interface: wan_interface.unwrap_or_else(|| "eth0".to_string()),
+            session_uplinks,
+            pppoe_backend: pppoe_backend.unwrap_or(PppoeBackend::Pppd),
+        };
+        config.check_ports()?;
+        Ok(config)
+    }
 
-        let health_check_target =
-            env::var("HEALTH_CHECK_TARGET").unwrap_or_else(|_| "8.8.8.8".to_string());
+    /// Every port ppproxy and gost listen on, with what listens there.
+    fn ports(&self) -> Vec<(String, u16)> {
+        let mut ports = vec![
+            ("the WAN proxy".to_string(), PROXY_PORT_BASE),
+            ("the tun0 service".to_string(), TUN_PORT_BASE),
+            ("the gost API".to_string(), GOST_API_PORT),
+            ("gost metrics".to_string(), GOST_METRICS_PORT),
+        ];
+        for i in 0..self.session_count {
+            ports.push((format!("the ppp{} proxy", i), PROXY_PORT_BASE + 1 + i));
+            ports.push((format!("the tun{} service", i + 1), TUN_PORT_BASE + 1 + i));
+        }
+        let configured = [
+            ("proxy.selector_port", self.proxy.selector_port),
+            ("proxy.balancer_port", self.proxy.balancer_port),
+            ("api.port", self.api.port),
+        ];
+        for (name, port) in configured {
+            if port != 0 {
+                ports.push((name.to_string(), port));
+            }
+        }
+        ports
+    }
 
-        let gateway = env::var("GATEWAY").context("GATEWAY not set")?;
+    /// Fails if two listeners would get the same port.
+    pub fn check_ports(&self) -> Result<()> {
+        let mut used = BTreeMap::new();
+        for (name, port) in self.ports() {
+            if let Some(other) = used.insert(port, name.clone()) {
+                return Err(anyhow!(
+                    "Port {} is used by both {} and {}",
+                    port,
+                    other,
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
 
-        let ip_rotation = IpRotationConfig {
-            rotation_time,
-            wait_seconds,
-            health_check_enabled,
-            health_check_interval_secs,
-            health_check_failure_threshold,
-            health_check_target,
-        };
+/// Overrides file values with the env vars that are set.
+fn apply_env(file: &mut FileConfig) -> Result<()> {
+    env_override("GATEWAY", &mut file.gateway)?;
+    env_override("WAN_INTERFACE", &mut file.wan_interface)?;
+    if let Some(value) = env_value("PPPOE_SESSION_UPLINKS") {
+        file.session_uplinks = Some(parse_session_map("PPPOE_SESSION_UPLINKS", &value)?);
+    }
+    env_override("PPPOE_BACKEND", &mut file.pppoe_backend)?;
+    env_override("IP_HISTORY_PATH", &mut file.history_path)?;
+    env_override("RUST_LOG", &mut file.log)?;
 
-        Ok(Self {
-            username,
-            password,
-            session_count,
-            ip_rotation,
-            logger_level,
-            discord_token,
-            discord_guild_id,
-            gateway,
-        })
-    }
-}
-
-fn is_valid_time_format(time: &str) -> bool {
-    let parts: Vec<&str> = time.split(':').collect();
-    if parts.len() != 2 {
-        return false;
-    }
-    let hour = parts[0].parse::<u32>();
-    let minute = parts[1].parse::<u32>();
-    matches!((hour, minute), (Ok(h), Ok(m)) if h < 24 && m < 60)
-}
-
-pub fn time_string_to_sec(time_str: &str) -> Result<i64> {
-    let parts: Vec<&str> = time_str.split(':').collect();
-    if parts.len() != 2 {
-        return Err(anyhow!("Invalid time format: {}", time_str));
-    }
-    let hour: u32 = parts[0]
-        .parse()
-        .map_err(|_| anyhow!("Invalid hour: {}", parts[0]))?;
-    let minute: u32 = parts[1]
-        .parse()
-        .map_err(|_| anyhow!("Invalid minute: {}", parts[1]))?;
-    let local_now = Local::now();
-
-    let next_time = local_now
-        .with_hour(hour)
-        .unwrap()
-        .with_minute(minute)
-        .unwrap()
-        .with_second(0)
-        .unwrap();
-    let next_time = if next_time < local_now {
-        next_time + chrono::Duration::days(1)
-    } else {
-        next_time
-    };
-    debug!(
-        "Current local time: {}",
-        local_now.format("%Y-%m-%d %H:%M:%S")
-    );
-    debug!(
-        "Next rotation time: {}",
-        next_time.format("%Y-%m-%d %H:%M:%S")
-    );
-    Ok(next_time.timestamp() - local_now.timestamp())
+    let session_log = &mut file.session_log;
+    env_override("SESSION_LOG_LINES", &mut session_log.lines)?;
+    env_override("SESSION_LOG_DIR", &mut session_log.dir)?;
+    env_override("SESSION_LOG_MAX_BYTES", &mut session_log.max_bytes)?;
+    env_override("SESSION_LOG_MAX_FILES", &mut session_log.max_files)?;
+
+    env_override("PPPOE_USERNAME", &mut file.pppoe.username)?;
+    env_override("PPPOE_PASSWORD", &mut file.pppoe.password)?;
+    env_override("PPPOE_SESSION_COUNT", &mut file.pppoe.session_count)?;
+    if let Some(value) = env_value("PPPOE_UPLINKS") {
+        file.pppoe.uplinks = Some(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|uplink| !uplink.is_empty())
+                .map(str::to_string)
+                .collect(),
+        );
+    }
+
+    let rotation = &mut file.rotation;
+    env_override("IP_ROTATION_TIME", &mut rotation.schedule)?;
+    if let Some(value) = env_value("IP_ROTATION_SCHEDULES") {
+        rotation.sessions = Some(parse_session_map("IP_ROTATION_SCHEDULES", &value)?);
+    }
+    env_override("IP_ROTATION_TIMEZONE", &mut rotation.timezone)?;
+    env_override("IP_ROTATION_WAIT_SECONDS", &mut rotation.wait_seconds)?;
+    env_override("IP_ROTATION_MODE", &mut rotation.mode)?;
+    env_override("IP_ROTATION_BATCH_SIZE", &mut rotation.batch_size)?;
+    env_override("IP_ROTATION_TIMEOUT", &mut rotation.timeout)?;
+    env_override("IP_ROTATION_MAX_REROLLS", &mut rotation.max_rerolls)?;
+
+    if let Some(value) = env_value("IP_DENY_CIDRS") {
+        file.ip_filter.deny = Some(parse_cidrs("IP_DENY_CIDRS", &value)?);
+    }
+    if let Some(value) = env_value("IP_PREFER_CIDRS") {
+        file.ip_filter.prefer = Some(parse_cidrs("IP_PREFER_CIDRS", &value)?);
+    }
+    env_override("IP_FILTER_MAX_ATTEMPTS", &mut file.ip_filter.max_attempts)?;
+
+    let health_check = &mut file.health_check;
+    env_override("HEALTH_CHECK_ENABLED", &mut health_check.enabled)?;
+    env_override("HEALTH_CHECK_INTERVAL", &mut health_check.interval)?;
+    env_override("HEALTH_CHECK_THRESHOLD", &mut health_check.threshold)?;
+    env_override("HEALTH_CHECK_TARGET", &mut health_check.target)?;
+
+    let proxy = &mut file.proxy;
+    env_override("PROXY_SELECTOR_PORT", &mut proxy.selector_port)?;
+    env_override("PROXY_BALANCER_PORT", &mut proxy.balancer_port)?;
+    env_override("PROXY_BALANCE_STRATEGY", &mut proxy.balance_strategy)?;
+    env_override("PROXY_STICKY_KEY", &mut proxy.sticky_key)?;
+    env_override("PROXY_STICKY_TTL", &mut proxy.sticky_ttl)?;
+    env_override("PROXY_STICKY_ON_IP_CHANGE", &mut proxy.sticky_on_ip_change)?;
+    env_override("GOST_LOG_LEVEL", &mut file.gost.log_level)?;
+
+    env_override("ROUTE_TABLE_BASE", &mut file.routing.table_base)?;
+    env_override("ROUTE_PRIORITY_BASE", &mut file.routing.priority_base)?;
+    env_override(
+        "ROUTE_RECONCILE_INTERVAL",
+        &mut file.routing.reconcile_interval,
+    )?;
+
+    env_override("API_PORT", &mut file.api.port)?;
+    env_override("API_ADDRESS", &mut file.api.address)?;
+    env_override("API_TOKEN", &mut file.api.token)?;
+
+    env_override("DISCORD_TOKEN", &mut file.discord.token)?;
+    env_override("DISCORD_GUILD_ID", &mut file.discord.guild_id)?;
+    env_override("DISCORD_ALERT_CHANNEL", &mut file.discord.alert_channel)?;
+    Ok(())
+}
+
+/// Parses a comma separated list of CIDRs.
+fn parse_cidrs(var: &str, value: &str) -> Result<Vec<IpNet>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|cidr| !cidr.is_empty())
+        .map(|cidr| parse_cidr(cidr).with_context(|| format!("Invalid {}", var)))
+        .collect()
+}
+
+/// Parses per-session values such as `ppp0=10;ppp1=04:00`.
+fn parse_session_map<T: FromStr>(var: &str, value: &str) -> Result<BTreeMap<String, T>>
+where
+    T::Err: Into<anyhow::Error>,
+{
+    let mut values = BTreeMap::new();
+    for entry in value.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let (interface, value) = entry.split_once('=').ok_or_else(|| {
+            anyhow!(
+                "Invalid {} entry: {}. Must be <interface>=<value>",
+                var,
+                entry
+            )
+        })?;
+        let value = value
+            .trim()
+            .parse()
+            .map_err(Into::into)
+            .with_context(|| format!("Invalid {} entry for {}", var, interface))?;
+        values.insert(interface.trim().to_string(), value);
+    }
+    Ok(values)
 }
@@ -0,0 +1,236 @@
+use anyhow::{Context, Result, anyhow};
+use ipnet::IpNet;
+use serde::Deserialize;
+use serde::de::{self, Deserializer};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::core::config::{BalanceStrategy, PppoeBackend, RotationMode, StickyKey, StickyPolicy};
+use crate::core::schedule::{RotationSchedule, ScheduleTimezone};
+
+/// A TOML/YAML scalar, so `batch_size = 2` and `batch_size = "2"` read the same.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Scalar {
+    String(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl std::fmt::Display for Scalar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scalar::String(s) => write!(f, "{}", s),
+            Scalar::Int(n) => write!(f, "{}", n),
+            Scalar::Float(n) => write!(f, "{}", n),
+            Scalar::Bool(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+/// Deserializes through `FromStr`, so file values are validated exactly like env vars.
+macro_rules! deserialize_from_str {
+    ($($ty:ty),*) => {$(
+        impl<'de> Deserialize<'de> for $ty {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                Scalar::deserialize(deserializer)?
+                    .to_string()
+                    .parse()
+                    .map_err(|e: anyhow::Error| de::Error::custom(format!("{:#}", e)))
+            }
+        }
+    )*};
+}
+
+deserialize_from_str!(
+    PppoeBackend,
+    RotationMode,
+    RotationSchedule,
+    ScheduleTimezone,
+    BalanceStrategy,
+    StickyKey,
+    StickyPolicy
+);
+
+/// A CIDR; a bare address is a single-host range.
+pub fn parse_cidr(s: &str) -> Result<IpNet> {
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| anyhow!("{} is not a CIDR", s))
+}
+
+fn cidrs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<IpNet>>, D::Error> {
+    let Some(cidrs) = Option::<Vec<String>>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    cidrs
+        .iter()
+        .map(|cidr| parse_cidr(cidr.trim()).map_err(de::Error::custom))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+/// The config file. Every key is optional here: env vars override what is set, and
+/// required settings are checked once both are merged.
+///
+/// `config.example.toml` documents every key and the env var that overrides it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub gateway: Option<String>,
+    pub wan_interface: Option<String>,
+    /// Uplinks of single sessions, by interface, overriding their account's.
+    pub session_uplinks: Option<BTreeMap<String, String>>,
+    pub pppoe_backend: Option<PppoeBackend>,
+    pub history_path: Option<String>,
+    pub log: Option<String>,
+    pub session_log: SessionLogSection,
+    pub pppoe: PppoeSection,
+    /// Several accounts instead of the single one in `pppoe`.
+    pub accounts: Vec<AccountSection>,
+    pub rotation: RotationSection,
+    pub ip_filter: IpFilterSection,
+    pub health_check: HealthCheckSection,
+    pub proxy: ProxySection,
+    pub gost: GostSection,
+    pub routing: RoutingSection,
+    pub api: ApiSection,
+    pub discord: DiscordSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionLogSection {
+    pub lines: Option<usize>,
+    pub dir: Option<String>,
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PppoeSection {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub session_count: Option<u16>,
+    pub uplinks: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountSection {
+    pub name: Option<String>,
+    pub username: String,
+    pub password: String,
+    pub sessions: u16,
+    #[serde(default)]
+    pub uplinks: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RotationSection {
+    pub schedule: Option<RotationSchedule>,
+    /// Per-session schedules, by interface.
+    pub sessions: Option<BTreeMap<String, RotationSchedule>>,
+    pub timezone: Option<ScheduleTimezone>,
+    pub wait_seconds: Option<u32>,
+    pub mode: Option<RotationMode>,
+    pub batch_size: Option<usize>,
+    pub timeout: Option<u64>,
+    pub max_rerolls: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpFilterSection {
+    #[serde(deserialize_with = "cidrs")]
+    pub deny: Option<Vec<IpNet>>,
+    #[serde(deserialize_with = "cidrs")]
+    pub prefer: Option<Vec<IpNet>>,
+    pub max_attempts: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheckSection {
+    pub enabled: Option<bool>,
+    pub interval: Option<u64>,
+    pub threshold: Option<u32>,
+    pub target: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxySection {
+    pub selector_port: Option<u16>,
+    pub balancer_port: Option<u16>,
+    pub balance_strategy: Option<BalanceStrategy>,
+    pub sticky_key: Option<StickyKey>,
+    pub sticky_ttl: Option<u64>,
+    pub sticky_on_ip_change: Option<StickyPolicy>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GostSection {
+    pub log_level: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutingSection {
+    pub table_base: Option<u32>,
+    pub priority_base: Option<u32>,
+    pub reconcile_interval: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiSection {
+    pub port: Option<u16>,
+    pub address: Option<IpAddr>,
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscordSection {
+    pub token: Option<String>,
+    pub guild_id: Option<u64>,
+    pub alert_channel: Option<u64>,
+}
+
+impl FileConfig {
+    /// Reads a `.yaml`/`.yml` file as YAML and anything else as TOML.
+    ///
+    /// Errors name the offending key, e.g. `rotation.batch_size: invalid digit found in string`.
+    pub fn read(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let yaml = path
+            .extension()
+            .is_some_and(|ext| ext == "yaml" || ext == "yml");
+        let config = if yaml {
+            // Going through a `Value` leaves the key path to serde_path_to_error, which
+            // names the exact key where serde_norway would only name its parent.
+            serde_norway::from_str::<serde_norway::Value>(&content)
+                .map_err(anyhow::Error::from)
+                .and_then(|value| {
+                    serde_path_to_error::deserialize(value)
+                        .map_err(|e| anyhow!("{}: {}", e.path(), e.inner()))
+                })
+        } else {
+            serde_path_to_error::deserialize(toml::Deserializer::new(&content)).map_err(|e| {
+                match e.path().to_string().as_str() {
+                    // Syntax errors have no key; the full error points at the line instead.
+                    "." => anyhow!("{}", e.inner()),
+                    path => anyhow!("{}: {}", path, e.inner().message()),
+                }
+            })
+        };
+        config.with_context(|| format!("Invalid config file {}", path.display()))
+    }
+}
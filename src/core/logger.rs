@@ -1,10 +1,35 @@
 use chrono::Local;
 use env_logger::Builder;
 use env_logger::fmt::style::{AnsiColor, Style};
+use log::{Log, Metadata, Record};
 use std::io::Write;
+use std::sync::{OnceLock, RwLock};
 
-pub fn init() {
-    Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+/// Forwards to an `env_logger` that can be swapped out when the filter changes.
+struct ReloadableLogger {
+    inner: RwLock<env_logger::Logger>,
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.inner.read().unwrap().log(record)
+    }
+
+    fn flush(&self) {
+        self.inner.read().unwrap().flush()
+    }
+}
+
+static LOGGER: OnceLock<ReloadableLogger> = OnceLock::new();
+
+/// `filter` uses the `RUST_LOG` syntax, e.g. `info` or `info,ppproxy::proxy=debug`.
+fn build(filter: &str) -> env_logger::Logger {
+    Builder::new()
+        .parse_filters(filter)
         .format(|buf, record| {
             let subtle = Style::new().fg_color(Some(AnsiColor::BrightBlack.into()));
             let level_style = buf.default_level_style(record.level());
@@ -20,5 +45,23 @@ pub fn init() {
         })
         .filter_module("serenity", log::LevelFilter::Warn)
         .filter_module("tracing", log::LevelFilter::Warn)
-        .init();
+        .build()
+}
+
+pub fn init(filter: &str) {
+    let logger = LOGGER.get_or_init(|| ReloadableLogger {
+        inner: RwLock::new(build(filter)),
+    });
+    log::set_max_level(logger.inner.read().unwrap().filter());
+    log::set_logger(logger).expect("Logger already initialized");
+}
+
+/// Replaces the filter of the logger set up by [`init`].
+pub fn set_filter(filter: &str) {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+    let inner = build(filter);
+    log::set_max_level(inner.filter());
+    *logger.inner.write().unwrap() = inner;
 }
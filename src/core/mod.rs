@@ -1,2 +1,4 @@
 pub mod config;
+pub mod config_file;
 pub mod logger;
+pub mod schedule;
@@ -0,0 +1,390 @@
+use anyhow::{Context, Error, Result, anyhow};
+use chrono::{
+    DateTime, Datelike, Duration, Local, LocalResult, NaiveDate, NaiveDateTime, NaiveTime,
+    TimeZone, Utc,
+};
+use chrono_tz::Tz;
+use std::str::FromStr;
+
+/// How far ahead to look for a day a cron expression matches; one without any in this
+/// window, such as `0 0 30 2 *`, is rejected.
+const MAX_CRON_DAYS: i64 = 366 * 5;
+
+const MONTH_NAMES: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const DAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Timezone rotation schedules are evaluated in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScheduleTimezone {
+    /// The process timezone, i.e. `TZ`.
+    #[default]
+    Local,
+    Named(Tz),
+}
+
+impl FromStr for ScheduleTimezone {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.eq_ignore_ascii_case("local") {
+            return Ok(ScheduleTimezone::Local);
+        }
+        s.parse::<Tz>()
+            .map(ScheduleTimezone::Named)
+            .map_err(|_| anyhow!("Unknown timezone: {}", s))
+    }
+}
+
+/// Resolves a wall-clock time, firing once in the earlier instant of a repeated DST hour and
+/// skipping times that fall in a DST gap.
+fn resolve_local<T: TimeZone>(tz: &T, naive: &NaiveDateTime) -> Option<DateTime<Utc>> {
+    match tz.from_local_datetime(naive) {
+        LocalResult::Single(t) => Some(t.with_timezone(&Utc)),
+        LocalResult::Ambiguous(earliest, _) => Some(earliest.with_timezone(&Utc)),
+        LocalResult::None => None,
+    }
+}
+
+/// A five-field cron expression: minute, hour, day of month, month, day of week.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    // Standard cron: when both day fields are restricted, either may match.
+    dom_restricted: bool,
+    dow_restricted: bool,
+}
+
+impl CronSchedule {
+    fn parse_value(value: &str, min: u32, names: &[&str]) -> Result<u32> {
+        if let Some(i) = names.iter().position(|n| n.eq_ignore_ascii_case(value)) {
+            return Ok(i as u32 + min);
+        }
+        value
+            .parse()
+            .map_err(|_| anyhow!("Invalid cron value: {}", value))
+    }
+
+    fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64> {
+        let mut bits = 0u64;
+        for part in field.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => {
+                    let step: u32 = step
+                        .parse()
+                        .map_err(|_| anyhow!("Invalid cron step: {}", part))?;
+                    if step == 0 {
+                        return Err(anyhow!("Invalid cron step: {}", part));
+                    }
+                    (range, step)
+                }
+                None => (part, 1),
+            };
+            let (start, end) = if range == "*" {
+                (min, max)
+            } else if let Some((start, end)) = range.split_once('-') {
+                (
+                    Self::parse_value(start, min, names)?,
+                    Self::parse_value(end, min, names)?,
+                )
+            } else {
+                let value = Self::parse_value(range, min, names)?;
+                // `5/15` means from 5 to the end of the range in steps of 15.
+                if part.contains('/') {
+                    (value, max)
+                } else {
+                    (value, value)
+                }
+            };
+            if start < min || end > max || start > end {
+                return Err(anyhow!("Cron field {} out of range {}-{}", part, min, max));
+            }
+            for value in (start..=end).step_by(step as usize) {
+                bits |= 1 << value;
+            }
+        }
+        Ok(bits)
+    }
+
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        if self.months & (1 << date.month()) == 0 {
+            return false;
+        }
+        let dom = self.days_of_month & (1 << date.day()) != 0;
+        let dow = self.days_of_week & (1 << date.weekday().num_days_from_sunday()) != 0;
+        match (self.dom_restricted, self.dow_restricted) {
+            (true, true) => dom || dow,
+            (true, false) => dom,
+            (false, true) => dow,
+            (false, false) => true,
+        }
+    }
+
+    fn next_in<T: TimeZone>(&self, tz: &T, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let local_now = now.with_timezone(tz).naive_local();
+        let today = local_now.date();
+        for days in 0..MAX_CRON_DAYS {
+            let date = today + Duration::days(days);
+            if !self.matches_date(date) {
+                continue;
+            }
+            for hour in (0..24).filter(|h| self.hours & (1 << h) != 0) {
+                for minute in (0..60).filter(|m| self.minutes & (1 << m) != 0) {
+                    let naive = date.and_hms_opt(hour, minute, 0)?;
+                    if naive <= local_now {
+                        continue;
+                    }
+                    if let Some(t) = resolve_local(tz, &naive).filter(|t| *t > now) {
+                        return Some(t);
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+impl FromStr for CronSchedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minute, hour, dom, month, dow] = fields[..] else {
+            return Err(anyhow!(
+                "Cron expression must have 5 fields, got {}: {}",
+                fields.len(),
+                s
+            ));
+        };
+        let mut days_of_week = Self::parse_field(dow, 0, 7, &DAY_NAMES)?;
+        // Both 0 and 7 mean Sunday.
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week |= 1;
+        }
+        let cron = Self {
+            minutes: Self::parse_field(minute, 0, 59, &[])?,
+            hours: Self::parse_field(hour, 0, 23, &[])?,
+            days_of_month: Self::parse_field(dom, 1, 31, &[])?,
+            months: Self::parse_field(month, 1, 12, &MONTH_NAMES)?,
+            days_of_week,
+            dom_restricted: dom != "*",
+            dow_restricted: dow != "*",
+        };
+        // Every field matches some time of day, so only the date fields can rule out all.
+        let today = Utc::now().date_naive();
+        if !(0..MAX_CRON_DAYS).any(|days| cron.matches_date(today + Duration::days(days))) {
+            return Err(anyhow!("Cron expression never matches: {}", s));
+        }
+        Ok(cron)
+    }
+}
+
+/// When a group of sessions gets a new IP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RotationSchedule {
+    Disabled,
+    /// Every N minutes.
+    Interval(u32),
+    /// Every day at each of these wall-clock times, e.g. `03:00,15:00`.
+    Daily(Vec<NaiveTime>),
+    /// A cron expression such as `*/15 9-18 * * 1-5`.
+    Cron(CronSchedule),
+}
+
+impl FromStr for RotationSchedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        match s.parse::<u32>() {
+            Ok(0) => return Ok(RotationSchedule::Disabled),
+            Ok(minutes) => return Ok(RotationSchedule::Interval(minutes)),
+            Err(_) => {}
+        }
+        if s.split_whitespace().count() > 1 {
+            return s.parse().map(RotationSchedule::Cron);
+        }
+        let mut times = s
+            .split(',')
+            .map(|t| NaiveTime::parse_from_str(t.trim(), "%H:%M"))
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| {
+                format!(
+                    "Invalid rotation schedule: {}. Must be minutes, HH:MM[,HH:MM...] or a cron expression",
+                    s
+                )
+            })?;
+        times.sort();
+        times.dedup();
+        Ok(RotationSchedule::Daily(times))
+    }
+}
+
+impl RotationSchedule {
+    /// The first rotation strictly after `now`, or `None` when rotation is disabled.
+    pub fn next_after(&self, now: DateTime<Utc>, tz: ScheduleTimezone) -> Option<DateTime<Utc>> {
+        match tz {
+            ScheduleTimezone::Local => self.next_in(&Local, now),
+            ScheduleTimezone::Named(tz) => self.next_in(&tz, now),
+        }
+    }
+
+    fn next_in<T: TimeZone>(&self, tz: &T, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            RotationSchedule::Disabled => None,
+            RotationSchedule::Interval(minutes) => Some(now + Duration::minutes(*minutes as i64)),
+            RotationSchedule::Daily(times) => {
+                let today = now.with_timezone(tz).date_naive();
+                (0..=2).find_map(|days| {
+                    let date = today + Duration::days(days);
+                    times
+                        .iter()
+                        .filter_map(|time| resolve_local(tz, &date.and_time(*time)))
+                        .find(|t| *t > now)
+                })
+            }
+            RotationSchedule::Cron(cron) => cron.next_in(tz, now),
+        }
+    }
+}
+
+/// Formats a UTC instant as wall-clock time in the schedule timezone.
+pub fn format_in(at: DateTime<Utc>, tz: ScheduleTimezone, fmt: &str) -> String {
+    match tz {
+        ScheduleTimezone::Local => at.with_timezone(&Local).format(fmt).to_string(),
+        ScheduleTimezone::Named(tz) => at.with_timezone(&tz).format(fmt).to_string(),
+    }
+}
+
+/// Parses an RFC 3339 timestamp, Unix seconds, or `YYYY-MM-DD HH:MM[:SS]` wall-clock time in
+/// the schedule timezone.
+pub fn parse_in(s: &str, tz: ScheduleTimezone) -> Result<DateTime<Utc>> {
+    let s = s.trim();
+    if let Ok(at) = DateTime::parse_from_rfc3339(s) {
+        return Ok(at.with_timezone(&Utc));
+    }
+    if let Ok(secs) = s.parse::<i64>() {
+        return DateTime::from_timestamp(secs, 0)
+            .ok_or_else(|| anyhow!("Invalid timestamp: {}", s));
+    }
+    let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M"))
+        .with_context(|| format!("Invalid time: {}. Must be YYYY-MM-DD HH:MM[:SS]", s))?;
+    let resolved = match tz {
+        ScheduleTimezone::Local => resolve_local(&Local, &naive),
+        ScheduleTimezone::Named(tz) => resolve_local(&tz, &naive),
+    };
+    resolved.ok_or_else(|| anyhow!("{} does not exist in {:?}", s, tz))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Europe::Berlin;
+
+    // In 2024 Berlin skips 02:00-03:00 on March 31 and repeats 02:00-03:00 on October 27.
+    const BERLIN: ScheduleTimezone = ScheduleTimezone::Named(Berlin);
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn naive(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn repeated_hour_resolves_to_earliest_instant() {
+        assert_eq!(
+            resolve_local(&Berlin, &naive("2024-10-27 02:30")),
+            Some(utc("2024-10-27T00:30:00Z"))
+        );
+    }
+
+    #[test]
+    fn skipped_hour_does_not_resolve() {
+        assert_eq!(resolve_local(&Berlin, &naive("2024-03-31 02:30")), None);
+        assert!(parse_in("2024-03-31 02:30", BERLIN).is_err());
+    }
+
+    #[test]
+    fn daily_time_in_gap_is_skipped_that_day() {
+        let schedule: RotationSchedule = "02:30".parse().unwrap();
+        assert_eq!(
+            schedule.next_after(utc("2024-03-30T12:00:00Z"), BERLIN),
+            Some(utc("2024-04-01T00:30:00Z"))
+        );
+    }
+
+    #[test]
+    fn daily_time_in_repeated_hour_fires_once() {
+        let schedule: RotationSchedule = "02:30".parse().unwrap();
+        let first = schedule
+            .next_after(utc("2024-10-26T12:00:00Z"), BERLIN)
+            .unwrap();
+        assert_eq!(first, utc("2024-10-27T00:30:00Z"));
+        assert_eq!(
+            schedule.next_after(first, BERLIN),
+            Some(utc("2024-10-28T01:30:00Z"))
+        );
+    }
+
+    #[test]
+    fn cron_skips_repeated_hour_at_fall_back() {
+        let schedule: RotationSchedule = "*/15 * * * *".parse().unwrap();
+        // 02:45 CEST; the next quarter hour is 03:00 CET, an hour and a quarter later.
+        assert_eq!(
+            schedule.next_after(utc("2024-10-27T00:45:00Z"), BERLIN),
+            Some(utc("2024-10-27T02:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn cron_skips_gap_at_spring_forward() {
+        let schedule: RotationSchedule = "*/15 * * * *".parse().unwrap();
+        // 01:50 CET; 02:00-02:45 do not exist, so 03:00 CEST is next.
+        assert_eq!(
+            schedule.next_after(utc("2024-03-31T00:50:00Z"), BERLIN),
+            Some(utc("2024-03-31T01:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn cron_at_fixed_time_in_gap_waits_a_day() {
+        let schedule: RotationSchedule = "30 2 * * *".parse().unwrap();
+        assert_eq!(
+            schedule.next_after(utc("2024-03-30T12:00:00Z"), BERLIN),
+            Some(utc("2024-04-01T00:30:00Z"))
+        );
+    }
+
+    #[test]
+    fn zero_minutes_disables_rotation() {
+        for s in ["0", "00", " 000 "] {
+            assert_eq!(
+                s.parse::<RotationSchedule>().unwrap(),
+                RotationSchedule::Disabled
+            );
+        }
+        assert_eq!(
+            "05".parse::<RotationSchedule>().unwrap(),
+            RotationSchedule::Interval(5)
+        );
+    }
+
+    #[test]
+    fn cron_that_never_matches_is_rejected() {
+        for s in ["0 0 30 2 *", "0 0 31 4,6,9,11 *"] {
+            assert!(s.parse::<CronSchedule>().is_err(), "{}", s);
+        }
+        // February 29 comes around within the search window.
+        assert!("0 0 29 2 *".parse::<CronSchedule>().is_ok());
+        // With both day fields restricted either matching is enough.
+        assert!("0 0 30 2 mon".parse::<CronSchedule>().is_ok());
+    }
+}
@@ -1,47 +1,70 @@
 use log::{error, info};
 use std::sync::Arc;
-use tokio::process::Command;
+use tokio::signal::unix::{SignalKind, signal};
 use tokio::sync::mpsc;
 
+mod api;
 mod bot;
 mod core;
 mod network;
 mod pppoe;
 mod proxy;
+mod runtime;
 
+use crate::api::ApiServer;
 use crate::core::config::AppConfig;
 use crate::core::logger;
+use crate::network::allocator::RouteAllocator;
+use crate::network::netlink::Routing;
+use crate::network::nft;
 use crate::network::route::init_route;
+use crate::pppoe::history::IpHistory;
 use crate::pppoe::manager::PPPoEManager;
+use crate::pppoe::session_log::SessionLogs;
+use crate::proxy::listener::ProxyListeners;
 use crate::proxy::server::ProxyServer;
-use anyhow::{Context, Result};
+use crate::runtime::Runtime;
+use anyhow::Result;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Load config first to get dry_run flag
     let config = AppConfig::load()?;
 
-    setup_nft().await?;
+    nft::apply(&nft::ruleset(&config.wan_interface, config.session_count)).await?;
 
-    logger::init();
+    logger::init(&config.log_filter);
 
-    let _ = init_route(&config.gateway)
-        .await
-        .map_err(|x| error!("{x:?}"));
+    let routing = Arc::new(Routing::new()?);
+    let routes = Arc::new(RouteAllocator::new(routing, &config.routing));
+    if let Err(e) = routes.sweep_stale().await {
+        error!("Failed to remove rules left by a previous run: {}", e);
+    }
+    let _ = init_route(
+        &config.gateway,
+        &config.wan_interface,
+        config.session_count,
+        &routes,
+    )
+    .await
+    .map_err(|x| error!("{x:?}"));
 
     let (event_tx, event_rx) = mpsc::channel(100);
 
-    let pppoe_manager = PPPoEManager::new(config.ip_rotation.clone());
+    let history = Arc::new(IpHistory::open(&config.history_path).await?);
+    let logs = SessionLogs::new(config.session_log.clone());
+    let pppoe_manager = PPPoEManager::new(
+        config.ip_rotation.clone(),
+        history,
+        logs,
+        Arc::clone(&routes),
+    );
     pppoe_manager.set_event_receiver(event_rx).await;
     PPPoEManager::start_stats_task(Arc::clone(&pppoe_manager)).await;
+    PPPoEManager::start_reconcile_task(Arc::clone(&pppoe_manager)).await;
 
     pppoe_manager
-        .start_clients(
-            config.username.clone(),
-            config.password.clone(),
-            config.session_count,
-            event_tx,
-        )
+        .start_clients(&config.sessions(), event_tx)
         .await;
 
     let pppoe_manager_clone = Arc::clone(&pppoe_manager);
@@ -49,51 +72,80 @@ async fn main() -> Result<()> {
         pppoe_manager_clone.run_event_loop().await;
     });
 
-    let pppoe_manager_clone = Arc::clone(&pppoe_manager);
-    tokio::spawn(async move {
-        pppoe_manager_clone.serve().await;
-    });
+    Arc::clone(&pppoe_manager).serve().await;
 
-    let pppoe_manager_clone = Arc::clone(&pppoe_manager);
+    let proxy = ProxyServer::new(config.session_count, config.logger_level.clone());
+    ProxyServer::start(Arc::clone(&proxy)).await;
+
+    let listeners = ProxyListeners::new(Arc::clone(&pppoe_manager), &config.proxy);
+    ProxyListeners::start(
+        Arc::clone(&listeners),
+        &config.wan_interface,
+        config.session_count,
+        &config.proxy,
+    )
+    .await;
+
+    let runtime = Runtime::new(
+        config.clone(),
+        Arc::clone(&pppoe_manager),
+        Arc::clone(&routes),
+        proxy,
+        listeners,
+    );
+
+    let runtime_clone = Arc::clone(&runtime);
     let discord_token = config.discord_token.clone();
     let discord_guild_id = config.discord_guild_id;
+    let discord_alert_channel = config.discord_alert_channel;
     tokio::spawn(async move {
-        if let Err(e) = bot::start_bot(discord_token, discord_guild_id, pppoe_manager_clone).await {
+        if let Err(e) = bot::start_bot(
+            discord_token,
+            discord_guild_id,
+            discord_alert_channel,
+            runtime_clone,
+        )
+        .await
+        {
             error!("Discord bot error: {:?}", e);
         }
     });
 
-    let proxy = ProxyServer::new(config.session_count, config.logger_level.clone());
-    ProxyServer::start(Arc::clone(&proxy)).await;
+    if config.api.port != 0 {
+        let api = ApiServer::new(Arc::clone(&runtime), &config.api);
+        let (address, port) = (config.api.address, config.api.port);
+        tokio::spawn(async move {
+            if let Err(e) = api.serve(address, port).await {
+                error!("API server error: {:?}", e);
+            }
+        });
+    }
 
-    info!("Service started. Press Ctrl+C to stop.");
+    info!("Service started. Ctrl+C or SIGTERM stops it, SIGHUP reloads the config.");
 
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
     loop {
         tokio::select! {
             _ = tokio::signal::ctrl_c() => {
                 info!("Shutting down...");
                 break;
             }
-            _ = tokio::time::sleep(tokio::time::Duration::from_secs(5)) => {
-                // Optional: Log stats periodically if needed, or rely on events
+            _ = terminate.recv() => {
+                info!("Received SIGTERM, shutting down...");
+                break;
+            }
+            _ = hangup.recv() => {
+                info!("Received SIGHUP, reloading configuration");
+                if let Err(e) = runtime.reload().await {
+                    error!("Failed to reload configuration: {:?}", e);
+                }
             }
         }
     }
 
-    info!("Stopping services...");
-    pppoe_manager.stop_all().await;
-    ProxyServer::stop(proxy).await;
+    runtime.shutdown().await;
     info!("Goodbye!");
 
     Ok(())
 }
-
-async fn setup_nft() -> Result<()> {
-    Command::new("nft")
-        .arg("-f")
-        .arg("/etc/nftables.conf")
-        .status()
-        .await
-        .context("Failed to execute nft command")?;
-    Ok(())
-}
@@ -0,0 +1,244 @@
+use anyhow::{Result, anyhow};
+use log::{debug, error, info};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::Duration;
+
+use crate::core::config::RoutingConfig;
+use crate::network::netlink::{DefaultRoute, PolicyRule, Routing, RoutingError};
+
+/// Files iproute2 reads table names from.
+const RT_TABLES_PATHS: [&str; 2] = ["/etc/iproute2/rt_tables", "/usr/share/iproute2/rt_tables"];
+
+/// `local`, `main`, `default` and `unspec`.
+const RESERVED_TABLES: [u32; 4] = [0, 253, 254, 255];
+
+/// Rule priorities from here on hold the kernel's `main` and `default` lookups.
+const MAX_RULE_PRIORITY: u32 = 32765;
+
+/// A routing table and the `ip rule` priority that sends a tun's traffic to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RouteAllocation {
+    pub table: u32,
+    pub priority: u32,
+}
+
+impl RouteAllocation {
+    pub fn rule(&self, tun: &str) -> PolicyRule {
+        PolicyRule {
+            iif: tun.to_string(),
+            table: self.table,
+            priority: self.priority,
+        }
+    }
+}
+
+/// Something the reconciler found missing from the kernel and put back.
+#[derive(Debug, Clone)]
+pub enum RouteRepair {
+    Rule(PolicyRule),
+    DefaultRoute(DefaultRoute),
+}
+
+impl std::fmt::Display for RouteRepair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteRepair::Rule(rule) => write!(f, "restored {}", rule),
+            RouteRepair::DefaultRoute(route) => write!(f, "restored {}", route),
+        }
+    }
+}
+
+/// Tables named in the iproute2 config, which other software may refer to by name.
+async fn named_tables() -> Vec<u32> {
+    let mut tables = Vec::new();
+    for path in RT_TABLES_PATHS {
+        let Ok(content) = tokio::fs::read_to_string(path).await else {
+            continue;
+        };
+        for line in content.lines() {
+            let mut parts = line.split_whitespace();
+            if let (Some(id), Some(_name)) = (parts.next(), parts.next())
+                && let Ok(id) = id.parse()
+            {
+                tables.push(id);
+            }
+        }
+    }
+    tables
+}
+
+/// Hands out routing tables and rule priorities for tun interfaces and removes them again.
+///
+/// Tables and priorities already used on the host are skipped, so several instances or
+/// other software can share the network namespace.
+pub struct RouteAllocator {
+    routing: Arc<Routing>,
+    table_base: u32,
+    priority_base: u32,
+    reconcile_interval: Duration,
+    allocations: Mutex<BTreeMap<String, RouteAllocation>>,
+    /// Default routes that should be in the kernel, by tun.
+    default_routes: Mutex<BTreeMap<String, DefaultRoute>>,
+}
+
+impl RouteAllocator {
+    pub fn new(routing: Arc<Routing>, config: &RoutingConfig) -> Self {
+        Self {
+            routing,
+            table_base: config.table_base,
+            priority_base: config.priority_base,
+            reconcile_interval: Duration::from_secs(config.reconcile_interval_secs),
+            allocations: Mutex::new(BTreeMap::new()),
+            default_routes: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn routing(&self) -> &Routing {
+        &self.routing
+    }
+
+    /// Picks a free table and priority for `tun` and installs its `ip rule`.
+    ///
+    /// Returns the existing allocation if `tun` already has one.
+    pub async fn allocate(&self, tun: &str) -> Result<RouteAllocation> {
+        let mut allocations = self.allocations.lock().await;
+        if let Some(allocation) = allocations.get(tun) {
+            return Ok(*allocation);
+        }
+
+        let mut host = self.routing.usage().await?;
+        host.tables.extend(RESERVED_TABLES);
+        host.tables.extend(named_tables().await);
+        for allocation in allocations.values() {
+            host.tables.insert(allocation.table);
+            host.priorities.insert(allocation.priority);
+        }
+        let table = (self.table_base..u32::MAX)
+            .find(|table| !host.tables.contains(table))
+            .ok_or_else(|| anyhow!("No free routing table for {}", tun))?;
+        let priority = (self.priority_base..=MAX_RULE_PRIORITY)
+            .find(|priority| !host.priorities.contains(priority))
+            .ok_or_else(|| anyhow!("No free rule priority for {}", tun))?;
+
+        let allocation = RouteAllocation { table, priority };
+        self.routing.add_rule(&allocation.rule(tun)).await?;
+        info!(
+            "{}: Allocated table {} with rule priority {}",
+            tun, table, priority
+        );
+        allocations.insert(tun.to_string(), allocation);
+        Ok(allocation)
+    }
+
+    /// Removes the rules, and their tables' routes, that a previous run left behind when
+    /// it did not get to tear down: rules sending a `tunN` interface to a table and
+    /// priority within this allocator's ranges. Tun names are unique in the namespace, so
+    /// no other running instance can own such a rule. Call before allocating anything.
+    pub async fn sweep_stale(&self) -> Result<Vec<PolicyRule>> {
+        let stale: Vec<PolicyRule> = self
+            .routing
+            .rules()
+            .await?
+            .into_iter()
+            .filter(|rule| {
+                rule.iif
+                    .strip_prefix("tun")
+                    .is_some_and(|n| n.parse::<u32>().is_ok())
+                    && rule.table >= self.table_base
+                    && (self.priority_base..=MAX_RULE_PRIORITY).contains(&rule.priority)
+            })
+            .collect();
+        for rule in &stale {
+            self.routing.delete_rule(rule).await?;
+            self.routing.flush_table(rule.table).await?;
+            info!("Removed stale {}", rule);
+        }
+        Ok(stale)
+    }
+
+    pub fn reconcile_interval(&self) -> Duration {
+        self.reconcile_interval
+    }
+
+    pub async fn get(&self, tun: &str) -> Option<RouteAllocation> {
+        self.allocations.lock().await.get(tun).copied()
+    }
+
+    /// Installs the default route of `tun`'s table and keeps it in place from then on.
+    pub async fn install_default_route(&self, tun: &str, route: DefaultRoute) -> Result<()> {
+        self.default_routes
+            .lock()
+            .await
+            .insert(tun.to_string(), route.clone());
+        self.routing.replace_default_route(&route).await?;
+        Ok(())
+    }
+
+    /// Stops keeping `tun`'s default route, e.g. because its session went down.
+    pub async fn forget_default_route(&self, tun: &str) {
+        self.default_routes.lock().await.remove(tun);
+    }
+
+    /// Puts back allocated rules and expected default routes missing from the kernel.
+    pub async fn reconcile(&self) -> Result<Vec<RouteRepair>> {
+        let expected_rules: Vec<PolicyRule> = self
+            .allocations
+            .lock()
+            .await
+            .iter()
+            .map(|(tun, allocation)| allocation.rule(tun))
+            .collect();
+        let expected_routes: Vec<DefaultRoute> =
+            self.default_routes.lock().await.values().cloned().collect();
+        let rules = self.routing.rules().await?;
+        let routes = self.routing.default_routes().await?;
+
+        let mut repairs = Vec::new();
+        for rule in expected_rules {
+            if rules.contains(&rule) {
+                continue;
+            }
+            match self.routing.add_rule(&rule).await {
+                Ok(()) => repairs.push(RouteRepair::Rule(rule)),
+                Err(e) => error!("Failed to restore {}: {}", rule, e),
+            }
+        }
+        for route in expected_routes {
+            if routes.contains(&route) {
+                continue;
+            }
+            match self.routing.replace_default_route(&route).await {
+                Ok(()) => repairs.push(RouteRepair::DefaultRoute(route)),
+                // The session just went down; its disconnect event clears the expectation.
+                Err(RoutingError::InterfaceNotFound(_)) => {}
+                Err(e) => error!("Failed to restore {}: {}", route, e),
+            }
+        }
+        Ok(repairs)
+    }
+
+    /// Removes the rule and table routes of `tun`, e.g. because its session was removed.
+    pub async fn release(&self, tun: &str) {
+        self.default_routes.lock().await.remove(tun);
+        let Some(allocation) = self.allocations.lock().await.remove(tun) else {
+            return;
+        };
+        if let Err(e) = self.routing.delete_rule(&allocation.rule(tun)).await {
+            error!("{}: Failed to remove rule: {}", tun, e);
+        }
+        if let Err(e) = self.routing.flush_table(allocation.table).await {
+            error!("{}: Failed to flush table {}: {}", tun, allocation.table, e);
+        }
+        debug!("{}: Released table {}", tun, allocation.table);
+    }
+
+    /// Removes every rule and table route this allocator installed.
+    pub async fn release_all(&self) {
+        let tuns: Vec<String> = self.allocations.lock().await.keys().cloned().collect();
+        for tun in tuns {
+            self.release(&tun).await;
+        }
+    }
+}
@@ -1 +1,4 @@
+pub mod allocator;
+pub mod netlink;
+pub mod nft;
 pub mod route;
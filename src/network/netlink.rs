@@ -0,0 +1,335 @@
+use futures::TryStreamExt;
+use netlink_packet_route::nlas::{link, route, rule};
+use netlink_packet_route::{FR_ACT_TO_TBL, RouteMessage, RuleMessage};
+use rtnetlink::{Handle, IpVersion};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum RoutingError {
+    #[error("Failed to open netlink socket: {0}")]
+    Connection(#[from] io::Error),
+    #[error("Interface {0} not found")]
+    InterfaceNotFound(String),
+    #[error("{0} already exists")]
+    AlreadyExists(String),
+    #[error("{0} does not exist")]
+    NotFound(String),
+    #[error("Not permitted to change {0}")]
+    PermissionDenied(String),
+    #[error("Failed to change {what}: {source}")]
+    Netlink {
+        what: String,
+        #[source]
+        source: rtnetlink::Error,
+    },
+}
+
+impl RoutingError {
+    /// Maps the kernel's errno onto the variants callers care about.
+    fn from_netlink(what: impl ToString, source: rtnetlink::Error) -> Self {
+        let what = what.to_string();
+        if let rtnetlink::Error::NetlinkError(message) = &source {
+            match message.to_io().kind() {
+                io::ErrorKind::AlreadyExists => return RoutingError::AlreadyExists(what),
+                io::ErrorKind::NotFound => return RoutingError::NotFound(what),
+                io::ErrorKind::PermissionDenied => return RoutingError::PermissionDenied(what),
+                _ => {}
+            }
+        }
+        RoutingError::Netlink { what, source }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, RoutingError>;
+
+/// `ip rule add iif <iif> table <table> priority <priority>`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub struct PolicyRule {
+    pub iif: String,
+    pub table: u32,
+    pub priority: u32,
+}
+
+impl std::fmt::Display for PolicyRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "rule iif {} table {} priority {}",
+            self.iif, self.table, self.priority
+        )
+    }
+}
+
+/// `ip route replace default [via <gateway>] dev <oif> table <table>`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub struct DefaultRoute {
+    pub table: u32,
+    pub oif: String,
+    pub gateway: Option<Ipv4Addr>,
+}
+
+impl std::fmt::Display for DefaultRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "default ")?;
+        if let Some(gateway) = self.gateway {
+            write!(f, "via {} ", gateway)?;
+        }
+        write!(f, "dev {} table {}", self.oif, self.table)
+    }
+}
+
+/// Tables and rule priorities that are in use, by us or anything else on the host.
+#[derive(Debug, Default)]
+pub struct RoutingUsage {
+    pub tables: BTreeSet<u32>,
+    pub priorities: BTreeSet<u32>,
+}
+
+fn route_table(message: &RouteMessage) -> u32 {
+    message
+        .nlas
+        .iter()
+        .find_map(|nla| match nla {
+            route::Nla::Table(table) => Some(*table),
+            _ => None,
+        })
+        .unwrap_or(message.header.table as u32)
+}
+
+fn rule_table(message: &RuleMessage) -> u32 {
+    message
+        .nlas
+        .iter()
+        .find_map(|nla| match nla {
+            rule::Nla::Table(table) => Some(*table),
+            _ => None,
+        })
+        .unwrap_or(message.header.table as u32)
+}
+
+fn rule_priority(message: &RuleMessage) -> u32 {
+    message
+        .nlas
+        .iter()
+        .find_map(|nla| match nla {
+            rule::Nla::Priority(priority) => Some(*priority),
+            _ => None,
+        })
+        .unwrap_or(0)
+}
+
+fn rule_iif(message: &RuleMessage) -> Option<&str> {
+    message.nlas.iter().find_map(|nla| match nla {
+        rule::Nla::Iifname(name) => Some(name.as_str()),
+        _ => None,
+    })
+}
+
+fn is_default(message: &RouteMessage) -> bool {
+    message.header.destination_prefix_length == 0
+}
+
+/// IPv4 policy rules and routes over rtnetlink.
+///
+/// Adds are idempotent where the kernel allows it: default routes are replaced and rules
+/// that already exist are left alone. Deleting something that is already gone succeeds.
+pub struct Routing {
+    handle: Handle,
+}
+
+impl Routing {
+    pub fn new() -> Result<Self> {
+        let (connection, handle, _) = rtnetlink::new_connection()?;
+        tokio::spawn(connection);
+        Ok(Self { handle })
+    }
+
+    async fn link_index(&self, name: &str) -> Result<u32> {
+        let link = self
+            .handle
+            .link()
+            .get()
+            .match_name(name.to_string())
+            .execute()
+            .try_next()
+            .await
+            .map_err(|e| match &e {
+                // A lookup by name fails with ENODEV when there is no such link.
+                rtnetlink::Error::NetlinkError(message)
+                    if message.to_io().raw_os_error() == Some(libc::ENODEV) =>
+                {
+                    RoutingError::InterfaceNotFound(name.to_string())
+                }
+                _ => RoutingError::from_netlink(format!("link {}", name), e),
+            })?;
+        link.map(|link| link.header.index)
+            .ok_or_else(|| RoutingError::InterfaceNotFound(name.to_string()))
+    }
+
+    async fn link_names(&self) -> Result<HashMap<u32, String>> {
+        let links: Vec<_> = self
+            .handle
+            .link()
+            .get()
+            .execute()
+            .try_collect()
+            .await
+            .map_err(|e| RoutingError::from_netlink("links", e))?;
+        Ok(links
+            .into_iter()
+            .filter_map(|message| {
+                let name = message.nlas.iter().find_map(|nla| match nla {
+                    link::Nla::IfName(name) => Some(name.clone()),
+                    _ => None,
+                })?;
+                Some((message.header.index, name))
+            })
+            .collect())
+    }
+
+    async fn route_messages(&self) -> Result<Vec<RouteMessage>> {
+        self.handle
+            .route()
+            .get(IpVersion::V4)
+            .execute()
+            .try_collect()
+            .await
+            .map_err(|e| RoutingError::from_netlink("routes", e))
+    }
+
+    async fn rule_messages(&self) -> Result<Vec<RuleMessage>> {
+        self.handle
+            .rule()
+            .get(IpVersion::V4)
+            .execute()
+            .try_collect()
+            .await
+            .map_err(|e| RoutingError::from_netlink("rules", e))
+    }
+
+    /// Every table referenced by a route or rule and every rule priority.
+    pub async fn usage(&self) -> Result<RoutingUsage> {
+        let mut usage = RoutingUsage::default();
+        for message in self.route_messages().await? {
+            usage.tables.insert(route_table(&message));
+        }
+        for message in self.rule_messages().await? {
+            usage.tables.insert(rule_table(&message));
+            usage.priorities.insert(rule_priority(&message));
+        }
+        Ok(usage)
+    }
+
+    /// Rules that match on an input interface, which is the only kind ppproxy installs.
+    pub async fn rules(&self) -> Result<Vec<PolicyRule>> {
+        Ok(self
+            .rule_messages()
+            .await?
+            .iter()
+            .filter_map(|message| {
+                Some(PolicyRule {
+                    iif: rule_iif(message)?.to_string(),
+                    table: rule_table(message),
+                    priority: rule_priority(message),
+                })
+            })
+            .collect())
+    }
+
+    pub async fn add_rule(&self, rule: &PolicyRule) -> Result<()> {
+        if self.rules().await?.contains(rule) {
+            return Ok(());
+        }
+        self.handle
+            .rule()
+            .add()
+            .v4()
+            .input_interface(rule.iif.clone())
+            .table_id(rule.table)
+            .priority(rule.priority)
+            .action(FR_ACT_TO_TBL)
+            .execute()
+            .await
+            .map_err(|e| RoutingError::from_netlink(rule, e))
+    }
+
+    pub async fn delete_rule(&self, rule: &PolicyRule) -> Result<()> {
+        for message in self.rule_messages().await? {
+            if rule_iif(&message) != Some(rule.iif.as_str())
+                || rule_table(&message) != rule.table
+                || rule_priority(&message) != rule.priority
+            {
+                continue;
+            }
+            if let Err(e) = self.handle.rule().del(message).execute().await {
+                match RoutingError::from_netlink(rule, e) {
+                    RoutingError::NotFound(_) => {}
+                    e => return Err(e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Default routes of every table, with interfaces resolved to names.
+    pub async fn default_routes(&self) -> Result<Vec<DefaultRoute>> {
+        let names = self.link_names().await?;
+        Ok(self
+            .route_messages()
+            .await?
+            .iter()
+            .filter(|message| is_default(message))
+            .filter_map(|message| {
+                let oif = names.get(&message.output_interface()?)?.clone();
+                let gateway = match message.gateway() {
+                    Some(IpAddr::V4(gateway)) => Some(gateway),
+                    _ => None,
+                };
+                Some(DefaultRoute {
+                    table: route_table(message),
+                    oif,
+                    gateway,
+                })
+            })
+            .collect())
+    }
+
+    pub async fn replace_default_route(&self, route: &DefaultRoute) -> Result<()> {
+        let index = self.link_index(&route.oif).await?;
+        let mut request = self
+            .handle
+            .route()
+            .add()
+            .v4()
+            .replace()
+            .output_interface(index)
+            .table_id(route.table);
+        if let Some(gateway) = route.gateway {
+            request = request.gateway(gateway);
+        }
+        request
+            .execute()
+            .await
+            .map_err(|e| RoutingError::from_netlink(route, e))
+    }
+
+    /// Removes every route in `table`.
+    pub async fn flush_table(&self, table: u32) -> Result<()> {
+        for message in self.route_messages().await? {
+            if route_table(&message) != table {
+                continue;
+            }
+            if let Err(e) = self.handle.route().del(message).execute().await {
+                match RoutingError::from_netlink(format!("route in table {}", table), e) {
+                    RoutingError::NotFound(_) => {}
+                    e => return Err(e),
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+use anyhow::{Context, Result, anyhow};
+use std::fmt::Write;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// Private ranges tun clients must not reach through a session.
+const RFC1918_CIDRS: [&str; 4] = [
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "127.0.0.0/8",
+];
+
+/// The tun → egress interface pairs: `tun0` goes out `wan`, `tun{i + 1}` out `ppp{i}`.
+fn tun_routes(wan: &str, session_count: u16) -> Vec<(String, String)> {
+    std::iter::once(("tun0".to_string(), wan.to_string()))
+        .chain((0..session_count).map(|i| (format!("tun{}", i + 1), format!("ppp{}", i))))
+        .collect()
+}
+
+/// Removes the tables ppproxy owns, named so that no table of the host's is touched;
+/// declaring them first makes the delete succeed either way.
+const TEARDOWN: &str = "table inet ppproxy {}
+delete table inet ppproxy
+table ip ppproxy_nat {}
+delete table ip ppproxy_nat
+";
+
+/// Builds the filter and NAT tables for `session_count` sessions, with clients reaching
+/// the proxies over `wan`.
+///
+/// Each table is declared, deleted and recreated so that applying the script replaces
+/// whatever a previous run left behind in a single transaction.
+pub fn ruleset(wan: &str, session_count: u16) -> String {
+    let routes = tun_routes(wan, session_count);
+    let mut forward = String::new();
+    let mut masquerade = String::new();
+    for (tun, egress) in &routes {
+        let _ = writeln!(
+            forward,
+            "        iifname \"{}\" oifname \"{}\" accept",
+            tun, egress
+        );
+        let _ = writeln!(
+            masquerade,
+            "        iifname \"{}\" oifname \"{}\" masquerade",
+            tun, egress
+        );
+    }
+
+    format!(
+        r#"{TEARDOWN}
+table inet ppproxy {{
+    set rfc1918_cidrs {{
+        type ipv4_addr
+        flags interval
+        elements = {{ {rfc1918} }}
+    }}
+
+    chain input {{
+        type filter hook input priority 0;
+        policy drop;
+
+        ct state {{ established, related }} accept
+
+        iifname "tun*" drop
+
+        iif "lo" accept
+        iifname "{wan}" accept
+        icmp type echo-reply accept
+    }}
+
+    chain forward {{
+        type filter hook forward priority 0;
+        policy drop;
+        ct state {{ established, related }} accept
+
+        iifname "tun*" ip daddr @rfc1918_cidrs drop
+
+{forward}    }}
+
+    chain output {{
+        type filter hook output priority 0;
+        policy accept;
+    }}
+}}
+
+table ip ppproxy_nat {{
+    chain postrouting {{
+        type nat hook postrouting priority 100;
+
+{masquerade}    }}
+}}
+"#,
+        rfc1918 = RFC1918_CIDRS.join(", "),
+    )
+}
+
+/// Loads a ruleset with `nft -f -`, which applies it atomically.
+pub async fn apply(ruleset: &str) -> Result<()> {
+    let mut child = Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to execute nft command")?;
+    let mut stdin = child.stdin.take().context("Failed to open nft stdin")?;
+    stdin.write_all(ruleset.as_bytes()).await?;
+    drop(stdin);
+
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "nft rejected the ruleset: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Deletes the filter and NAT tables installed by [`ruleset`].
+pub async fn teardown() -> Result<()> {
+    apply(TEARDOWN).await
+}
@@ -1,39 +1,40 @@
-use anyhow::Result;
-use log::error;
-use tokio::process::Command;
+use anyhow::{Context, Result};
+use std::net::Ipv4Addr;
 
-pub async fn init_route(gateway: &str) -> Result<()> {
-    for i in 0..8 {
-        let table_id = 100 + i;
-        Command::new("ip")
-            .args([
-                "rule",
-                "add",
-                "iif",
-                format!("tun{i}").as_str(),
-                "table",
-                &table_id.to_string(),
-                "prio",
-                &table_id.to_string(),
-            ])
-            .output()
-            .await
-            .map_err(|e| {
-                error!("Failed to add rule: {}", e);
-                e
-            })?;
-    }
+use crate::network::allocator::RouteAllocator;
+use crate::network::netlink::DefaultRoute;
+
+/// The tun whose traffic leaves through session `interface`: `ppp0` is served by `tun1`.
+pub fn session_tun(interface: &str) -> String {
+    let idx: u32 = interface.trim_start_matches("ppp").parse().unwrap_or(0);
+    format!("tun{}", idx + 1)
+}
 
-    Command::new("ip")
-        .args([
-            "route", "add", "default", "via", gateway, "dev", "eth0", "table", "100",
-        ])
-        .output()
-        .await
-        .map_err(|e| {
-            error!("Failed to add default route: {}", e);
-            e
-        })?;
+pub async fn init_route(
+    gateway: &str,
+    wan: &str,
+    session_count: u16,
+    routes: &RouteAllocator,
+) -> Result<()> {
+    let gateway: Ipv4Addr = gateway
+        .parse()
+        .with_context(|| format!("Invalid GATEWAY: {}", gateway))?;
+    for i in 0..=u32::from(session_count) {
+        routes.allocate(&format!("tun{i}")).await?;
+    }
 
+    if let Some(direct) = routes.get("tun0").await {
+        let route = DefaultRoute {
+            table: direct.table,
+            oif: wan.to_string(),
+            gateway: Some(gateway),
+        };
+        routes.install_default_route("tun0", route).await?;
+    }
     Ok(())
 }
+
+/// Removes the rules and table routes installed by [`init_route`] and the sessions.
+pub async fn teardown_route(routes: &RouteAllocator) {
+    routes.release_all().await;
+}
@@ -1,41 +1,163 @@
 use chrono::Utc;
-use log::{error, info};
+use log::{error, info, warn};
+use std::sync::Arc;
 use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::process::{Child, Command};
-use tokio::sync::mpsc;
+use tokio::process::{Child, ChildStderr, ChildStdout, Command};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant, sleep_until, timeout};
 
+use crate::core::config::{PppoeBackend, SessionConfig};
+use crate::pppoe::exit::ExitReason;
+use crate::pppoe::lease::{LogLine, PppdLog};
 use crate::pppoe::manager::{ClientCommand, PpmsEvent};
+use crate::pppoe::native::{self, NativeError};
+use crate::pppoe::session_log::{SessionLog, Stream};
+
+/// How long pppd gets to print the rest of the address lines after the local IP.
+const LEASE_SETTLE: Duration = Duration::from_millis(500);
+/// A connection that lasted this long worked, so its exit starts a new failure count.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// How a connection ended.
+struct Exit {
+    reason: ExitReason,
+    /// What pppd or the native client said about it.
+    detail: String,
+}
+
+/// A running connection attempt or session.
+enum Connection {
+    Pppd {
+        child: Child,
+        /// Reads the log and hands it back once pppd closes stdout.
+        log: JoinHandle<PppdLog>,
+        /// Reads stderr, where pppd reports what goes wrong before logging starts.
+        stderr: JoinHandle<Option<ExitReason>>,
+    },
+    Native {
+        task: JoinHandle<Result<(), NativeError>>,
+        stop: Option<oneshot::Sender<()>>,
+    },
+}
+
+impl Connection {
+    /// Waits for the connection to end and tells why it did.
+    async fn wait(&mut self) -> Exit {
+        match self {
+            Connection::Pppd { child, log, stderr } => {
+                let status = match child.wait().await {
+                    Ok(status) => status,
+                    Err(e) => {
+                        let detail = format!("failed to wait for pppd: {}", e);
+                        return Exit {
+                            reason: ExitReason::Other(detail.clone()),
+                            detail,
+                        };
+                    }
+                };
+                let log = timeout(LEASE_SETTLE, log).await.ok().and_then(Result::ok);
+                let termination = log
+                    .as_ref()
+                    .and_then(|log| log.lease.termination_reason.clone());
+                let hint = timeout(LEASE_SETTLE, stderr)
+                    .await
+                    .ok()
+                    .and_then(Result::ok)
+                    .flatten()
+                    .or_else(|| log.and_then(|log| log.exit_reason));
+                Exit {
+                    reason: ExitReason::from_pppd(status, hint),
+                    detail: match termination {
+                        Some(termination) => format!("{} (pppd {})", termination, status),
+                        None => format!("pppd process exited with {}", status),
+                    },
+                }
+            }
+            Connection::Native { task, .. } => match task.await {
+                Ok(Ok(())) => Exit {
+                    reason: ExitReason::Other("session closed".to_string()),
+                    detail: "session closed".to_string(),
+                },
+                Ok(Err(e)) => Exit {
+                    reason: ExitReason::from_native(&e),
+                    detail: format!("session ended: {}", e),
+                },
+                Err(e) => {
+                    let detail = format!("session task failed: {}", e);
+                    Exit {
+                        reason: ExitReason::Other(detail.clone()),
+                        detail,
+                    }
+                }
+            },
+        }
+    }
+
+    /// Hangs up, giving the native client a moment to tell the peer.
+    async fn stop(mut self, interface: &str) {
+        match &mut self {
+            Connection::Pppd { child, .. } => {
+                let _ = child.kill().await;
+                let _ = child.wait().await;
+            }
+            Connection::Native { task, stop } => {
+                if let Some(stop) = stop.take() {
+                    let _ = stop.send(());
+                }
+                if timeout(Duration::from_secs(5), &mut *task).await.is_err() {
+                    warn!("{}: session did not close in time, dropping it", interface);
+                    task.abort();
+                }
+            }
+        }
+    }
+}
 
 pub struct PPPoEClient {
     username: String,
     password: String,
     pub interface: String,
-    pppd: Option<Child>,
+    /// Ethernet device to run PPPoE discovery on.
+    uplink: String,
+    backend: PppoeBackend,
+    connection: Option<Connection>,
     event_sender: mpsc::Sender<PpmsEvent>,
     command_receiver: mpsc::Receiver<ClientCommand>,
+    /// Where pppd's output and the client's notes on each connection go.
+    log: Arc<SessionLog>,
     should_be_connected: bool,
-    reconnect_attempts: u32,
-    max_reconnect_attempts: u32,
+    /// When the current or last connection was dialed.
+    dialed_at: Option<Instant>,
+    /// Why the last connection ended, and how many times in a row it did so.
+    last_exit: Option<ExitReason>,
+    failures: u32,
+    /// When to dial again after a failure.
+    retry_at: Option<Instant>,
 }
 
 impl PPPoEClient {
     pub fn new(
-        username: String,
-        password: String,
-        interface: String,
+        session: &SessionConfig,
         event_sender: mpsc::Sender<PpmsEvent>,
         command_receiver: mpsc::Receiver<ClientCommand>,
+        log: Arc<SessionLog>,
     ) -> Self {
         Self {
-            username,
-            password,
-            interface,
-            pppd: None,
+            username: session.username.clone(),
+            password: session.password.clone(),
+            interface: session.interface.clone(),
+            uplink: session.uplink.clone(),
+            backend: session.backend,
+            connection: None,
             event_sender,
             command_receiver,
+            log,
             should_be_connected: false,
-            reconnect_attempts: 0,
-            max_reconnect_attempts: 0,
+            dialed_at: None,
+            last_exit: None,
+            failures: 0,
+            retry_at: None,
         }
     }
 
@@ -51,81 +173,173 @@ impl PPPoEClient {
                     match cmd {
                         ClientCommand::Connect => {
                             self.should_be_connected = true;
-                            if self.pppd.is_none() {
-                                self.reconnect_attempts = 0;
+                            if self.connection.is_none() {
+                                self.reset_retries();
                                 self.connect().await;
                             }
                         }
                         ClientCommand::Disconnect => {
                             self.should_be_connected = false;
-                            self.reconnect_attempts = 0;
+                            self.reset_retries();
                             self.disconnect().await;
                         }
                         ClientCommand::Reconnect => {
                             self.should_be_connected = true;
-                            self.reconnect_attempts = 0;
+                            self.reset_retries();
                             self.disconnect().await;
                             tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
                             self.connect().await;
                         }
+                        ClientCommand::Redial { after } => {
+                            self.should_be_connected = true;
+                            self.disconnect().await;
+                            self.retry_at = Some(Instant::now() + after);
+                        }
+                        ClientCommand::Configure { username, password, uplink, backend } => {
+                            self.username = username;
+                            self.password = password;
+                            self.uplink = uplink;
+                            self.backend = backend;
+                        }
+                        ClientCommand::Stop => {
+                            self.disconnect().await;
+                            break;
+                        }
                     }
                 }
-                Some(result) = async {
-                    if let Some(ref mut child) = self.pppd {
-                        child.wait().await.ok()
-                    } else {
-                        None
+                Some(exit) = async {
+                    match self.connection {
+                        Some(ref mut connection) => Some(connection.wait().await),
+                        None => None,
                     }
                 } => {
-                    info!("{}: pppd process exited with {:?}", self.interface, result);
-                    self.pppd = None;
+                    info!("{}: {} [{}]", self.interface, exit.detail, exit.reason);
+                    self.log.push(Stream::Client, &format!("{} [{}]", exit.detail, exit.reason));
+                    self.connection = None;
 
                     let _ = self.event_sender.send(PpmsEvent::Disconnected {
                         interface: self.interface.clone(),
+                        reason: Some(exit.detail),
                     }).await;
 
                     if self.should_be_connected {
-                        if self.max_reconnect_attempts == 0 || self.reconnect_attempts < self.max_reconnect_attempts {
-                            self.reconnect_attempts += 1;
-
-                            let delay = std::cmp::min(
-                                5 * self.reconnect_attempts as u64,
-                                30
-                            );
-
-                            info!(
-                                "{}: Auto-reconnecting in {} seconds (attempt {}/{})",
-                                self.interface,
-                                delay,
-                                self.reconnect_attempts,
-                                if self.max_reconnect_attempts == 0 { "∞".to_string() } else { self.max_reconnect_attempts.to_string() }
-                            );
-
-                            tokio::time::sleep(tokio::time::Duration::from_secs(delay)).await;
-                            self.connect().await;
-                        } else {
-                            error!(
-                                "{}: Max reconnection attempts ({}) reached, giving up",
-                                self.interface,
-                                self.max_reconnect_attempts
-                            );
-                            self.should_be_connected = false;
-                        }
+                        self.schedule_retry(exit.reason).await;
                     } else {
                         info!("{}: Manual disconnect, not auto-reconnecting", self.interface);
                     }
                 }
+                _ = async {
+                    match self.retry_at {
+                        Some(at) => sleep_until(at).await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    self.retry_at = None;
+                    self.connect().await;
+                }
             }
         }
+
+        info!("PPPoE Client {} stopped", self.interface);
+        let _ = self
+            .event_sender
+            .send(PpmsEvent::Stopped {
+                interface: self.interface.clone(),
+            })
+            .await;
+    }
+
+    fn reset_retries(&mut self) {
+        self.last_exit = None;
+        self.failures = 0;
+        self.retry_at = None;
+    }
+
+    /// Dials again after the delay `reason`'s policy asks for, or gives up and alerts
+    /// once it failed that way too often in a row.
+    async fn schedule_retry(&mut self, reason: ExitReason) {
+        let stable = self
+            .dialed_at
+            .is_some_and(|dialed_at| dialed_at.elapsed() >= STABLE_AFTER);
+        if stable || self.last_exit.as_ref() != Some(&reason) {
+            self.failures = 0;
+        }
+        self.failures += 1;
+        self.last_exit = Some(reason.clone());
+
+        let policy = reason.retry_policy();
+        if policy
+            .max_attempts
+            .is_some_and(|max_attempts| self.failures >= max_attempts)
+        {
+            error!(
+                "{}: Giving up after {} failure(s) in a row: {}",
+                self.interface, self.failures, reason
+            );
+            self.should_be_connected = false;
+            self.log.push(
+                Stream::Client,
+                &format!("Giving up after {} failure(s) in a row", self.failures),
+            );
+            let _ = self
+                .event_sender
+                .send(PpmsEvent::GaveUp {
+                    interface: self.interface.clone(),
+                    reason,
+                })
+                .await;
+            return;
+        }
+
+        let delay = policy.delay(self.failures);
+        info!(
+            "{}: Auto-reconnecting in {} seconds (attempt {}{})",
+            self.interface,
+            delay.as_secs(),
+            self.failures,
+            policy
+                .max_attempts
+                .map(|max_attempts| format!("/{}", max_attempts))
+                .unwrap_or_default()
+        );
+        self.retry_at = Some(Instant::now() + delay);
     }
 
     async fn connect(&mut self) {
-        info!("Connecting {}", self.interface);
+        info!("Connecting {} over {}", self.interface, self.uplink);
+        self.dialed_at = Some(Instant::now());
+        self.log.push(
+            Stream::Client,
+            &format!("Dialing over {} with {}", self.uplink, self.backend),
+        );
+
+        if self.backend == PppoeBackend::Native {
+            let (stop, stop_rx) = oneshot::channel();
+            let task = tokio::spawn(native::run(
+                self.interface.clone(),
+                self.uplink.clone(),
+                self.username.clone(),
+                self.password.clone(),
+                self.event_sender.clone(),
+                stop_rx,
+            ));
+            self.connection = Some(Connection::Native {
+                task,
+                stop: Some(stop),
+            });
+            return;
+        }
 
+        // The plugin and `debug` make pppd log the session ID, AC name and LCP options
+        // the lease is read from.
         let cmd = vec![
             "pppd".to_string(),
-            "pty".to_string(),
-            "pppoe".to_string(),
+            "plugin".to_string(),
+            "rp-pppoe.so".to_string(),
+            self.uplink.clone(),
+            "rp_pppoe_verbose".to_string(),
+            "1".to_string(),
+            "debug".to_string(),
             "noauth".to_string(),
             "nodetach".to_string(),
             "usepeerdns".to_string(),
@@ -145,58 +359,124 @@ impl PPPoEClient {
         {
             Ok(mut child) => {
                 let stdout = child.stdout.take().unwrap();
-                self.pppd = Some(child);
-
-                let interface = self.interface.clone();
-                let event_sender = self.event_sender.clone();
-
-                tokio::spawn(async move {
-                    let mut reader = BufReader::new(stdout);
-                    let mut line = String::new();
-                    let mut ip_obtained = false;
-                    while let Ok(n) = reader.read_line(&mut line).await {
-                        if n == 0 {
-                            break;
-                        }
-                        let trimmed = line.trim();
-                        if trimmed.contains("local  IP address") {
-                            let parts: Vec<&str> = trimmed.split_whitespace().collect();
-                            if parts.len() >= 4 {
-                                let local_ip = parts[3].to_string();
-                                ip_obtained = true;
-                                let _ = event_sender
-                                    .send(PpmsEvent::IpUpdated {
-                                        interface: interface.clone(),
-                                        local_ip: Some(local_ip),
-                                        connected_at: Some(Utc::now()),
-                                    })
-                                    .await;
-                            }
-                        }
-                        line.clear();
-                    }
-                    if ip_obtained {
-                        info!("{}: pppd stdout closed, connection likely lost", interface);
-                    }
-                });
+                let stderr = child.stderr.take().unwrap();
+                let log = tokio::spawn(read_pppd_log(
+                    stdout,
+                    self.interface.clone(),
+                    self.event_sender.clone(),
+                    Arc::clone(&self.log),
+                ));
+                let stderr = tokio::spawn(read_pppd_stderr(stderr, Arc::clone(&self.log)));
+                self.connection = Some(Connection::Pppd { child, log, stderr });
             }
             Err(e) => {
                 error!("Failed to start pppd for {}: {}", self.interface, e);
+                self.log
+                    .push(Stream::Client, &format!("Failed to start pppd: {}", e));
+                self.schedule_retry(ExitReason::Other(format!("failed to start pppd: {}", e)))
+                    .await;
             }
         }
     }
 
     async fn disconnect(&mut self) {
-        if let Some(mut child) = self.pppd.take() {
-            let _ = child.kill().await;
-            let _ = child.wait().await;
+        if let Some(connection) = self.connection.take() {
+            connection.stop(&self.interface).await;
         }
 
         let _ = self
             .event_sender
             .send(PpmsEvent::Disconnected {
                 interface: self.interface.clone(),
+                reason: Some("Disconnected on request".to_string()),
             })
             .await;
     }
 }
+
+/// Parses pppd's stdout into a lease, reporting it once the address lines following
+/// the local IP are in. Returns the parsed log, with the termination and exit reasons if
+/// pppd gave them.
+async fn read_pppd_log(
+    stdout: ChildStdout,
+    interface: String,
+    event_sender: mpsc::Sender<PpmsEvent>,
+    session_log: Arc<SessionLog>,
+) -> PppdLog {
+    let mut lines = BufReader::new(stdout).lines();
+    let mut log = PppdLog::default();
+    // Set from the local IP line until the lease is reported.
+    let mut connected_at = None;
+    let mut ip_obtained = false;
+    loop {
+        let line = if connected_at.is_some() {
+            match timeout(LEASE_SETTLE, lines.next_line()).await {
+                Ok(line) => line,
+                Err(_) => {
+                    report_lease(&interface, &mut log, connected_at.take(), &event_sender).await;
+                    continue;
+                }
+            }
+        } else {
+            lines.next_line().await
+        };
+        let Ok(Some(line)) = line else {
+            break;
+        };
+        session_log.push(Stream::Stdout, &line);
+        match log.feed(&line) {
+            LogLine::LocalIp => {
+                ip_obtained = true;
+                connected_at = Some(Utc::now());
+            }
+            LogLine::Address => {}
+            LogLine::Other if connected_at.is_some() => {
+                report_lease(&interface, &mut log, connected_at.take(), &event_sender).await;
+            }
+            LogLine::Other => {}
+        }
+    }
+    if connected_at.is_some() {
+        report_lease(&interface, &mut log, connected_at, &event_sender).await;
+    }
+    if ip_obtained {
+        info!("{}: pppd stdout closed, connection likely lost", interface);
+    }
+    log
+}
+
+/// Captures pppd's stderr and returns the first exit reason it gives.
+async fn read_pppd_stderr(stderr: ChildStderr, session_log: Arc<SessionLog>) -> Option<ExitReason> {
+    let mut lines = BufReader::new(stderr).lines();
+    let mut reason = None;
+    while let Ok(Some(line)) = lines.next_line().await {
+        session_log.push(Stream::Stderr, &line);
+        if reason.is_none() {
+            reason = ExitReason::from_line(&line);
+        }
+    }
+    reason
+}
+
+async fn report_lease(
+    interface: &str,
+    log: &mut PppdLog,
+    connected_at: Option<chrono::DateTime<Utc>>,
+    event_sender: &mpsc::Sender<PpmsEvent>,
+) {
+    // pppd only prints the MTU when the peer sent an MRU option, but the kernel knows it.
+    if log.lease.mtu.is_none() {
+        log.lease.mtu = tokio::fs::read_to_string(format!("/sys/class/net/{}/mtu", interface))
+            .await
+            .ok()
+            .and_then(|mtu| mtu.trim().parse().ok());
+    }
+    let _ = event_sender
+        .send(PpmsEvent::IpUpdated {
+            interface: interface.to_string(),
+            local_ip: log.local_ip.map(|ip| ip.to_string()),
+            connected_at,
+            lease: log.lease.clone(),
+        })
+        .await;
+}
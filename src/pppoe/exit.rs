@@ -0,0 +1,270 @@
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use tokio::time::Duration;
+
+use crate::pppoe::native::NativeError;
+
+/// Why a connection ended, which decides whether and when to dial again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The ISP rejected the username or password.
+    AuthFailed,
+    /// The peer stopped answering LCP echo requests.
+    PeerNotResponding,
+    /// No access concentrator answered discovery.
+    NoPado,
+    /// LCP or IPCP negotiation never finished.
+    LcpTimeout,
+    /// Ended by a signal, from us or someone else.
+    Killed,
+    /// The link went away under PPP.
+    ModemHangup,
+    /// The peer ended the session with an LCP Terminate-Request or a PADT.
+    TerminatedByPeer,
+    /// Every IP the ISP handed out was in a denied range.
+    IpDenied,
+    Other(String),
+}
+
+impl std::fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitReason::AuthFailed => write!(f, "authentication failed"),
+            ExitReason::PeerNotResponding => write!(f, "peer not responding"),
+            ExitReason::NoPado => write!(f, "no PADO"),
+            ExitReason::LcpTimeout => write!(f, "LCP timeout"),
+            ExitReason::Killed => write!(f, "killed"),
+            ExitReason::ModemHangup => write!(f, "modem hangup"),
+            ExitReason::TerminatedByPeer => write!(f, "terminated by peer"),
+            ExitReason::IpDenied => write!(f, "only denied IPs"),
+            ExitReason::Other(detail) => write!(f, "{}", detail),
+        }
+    }
+}
+
+/// How to go on after a connection ended for some reason.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Wait before the first retry, doubled after each failed one up to `max_delay`.
+    pub delay: Duration,
+    pub max_delay: Duration,
+    /// Failures in a row before giving up and alerting; `None` retries forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl RetryPolicy {
+    /// Wait before retry number `attempt`, counting from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(16);
+        self.delay
+            .saturating_mul(1 << doublings)
+            .min(self.max_delay)
+    }
+}
+
+/// pppd log lines that tell more than its exit code, the first one seen counting.
+const LINE_REASONS: &[(&str, ExitReason)] = &[
+    ("Timeout waiting for PADO", ExitReason::NoPado),
+    ("Timeout waiting for PADS", ExitReason::NoPado),
+    ("Unable to complete PPPoE Discovery", ExitReason::NoPado),
+    ("authentication failed", ExitReason::AuthFailed),
+    ("No response to", ExitReason::PeerNotResponding),
+    (
+        "LCP: timeout sending Config-Requests",
+        ExitReason::LcpTimeout,
+    ),
+    ("LCP terminated by peer", ExitReason::TerminatedByPeer),
+    ("Modem hangup", ExitReason::ModemHangup),
+    ("Terminating on signal", ExitReason::Killed),
+];
+
+impl ExitReason {
+    /// The reason a pppd or pppoe log line gives, if it is one of the known ones.
+    pub fn from_line(line: &str) -> Option<Self> {
+        LINE_REASONS
+            .iter()
+            .find(|(pattern, _)| line.contains(pattern))
+            .map(|(_, reason)| reason.clone())
+    }
+
+    /// Classifies a pppd exit by its status, preferring `hint` from its output, since
+    /// e.g. a failed discovery only shows up as a hangup in the exit code.
+    pub fn from_pppd(status: ExitStatus, hint: Option<ExitReason>) -> Self {
+        if status.signal().is_some() {
+            return ExitReason::Killed;
+        }
+        if let Some(hint) = hint {
+            return hint;
+        }
+        // See EXIT STATUS in pppd(8).
+        match status.code() {
+            Some(5) => ExitReason::Killed,
+            Some(10) => ExitReason::LcpTimeout,
+            Some(15) => ExitReason::PeerNotResponding,
+            Some(16) => ExitReason::ModemHangup,
+            Some(19) => ExitReason::AuthFailed,
+            Some(code) => ExitReason::Other(format!("pppd exit code {}", code)),
+            None => ExitReason::Other("pppd exited".to_string()),
+        }
+    }
+
+    pub fn from_native(error: &NativeError) -> Self {
+        match error {
+            NativeError::NoOffer(_) | NativeError::NoConfirmation => ExitReason::NoPado,
+            NativeError::AuthFailed(_) => ExitReason::AuthFailed,
+            NativeError::LcpTimeout | NativeError::AuthTimeout | NativeError::IpcpTimeout => {
+                ExitReason::LcpTimeout
+            }
+            NativeError::PeerNotResponding => ExitReason::PeerNotResponding,
+            NativeError::Terminated(_) | NativeError::Padt | NativeError::Renegotiated => {
+                ExitReason::TerminatedByPeer
+            }
+            _ => ExitReason::Other(error.to_string()),
+        }
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        let (delay, max_delay, max_attempts) = match self {
+            // A few tries, since some ISPs reject a login while the last session still
+            // lingers on their side; after that the password is most likely wrong.
+            ExitReason::AuthFailed => (30, 120, Some(3)),
+            // Likely the line or the ISP is down; keep trying, but not too often.
+            ExitReason::NoPado => (10, 300, None),
+            ExitReason::LcpTimeout => (5, 60, None),
+            ExitReason::Killed => (2, 2, None),
+            // The manager paces these redials and gives up after `ip_filter.max_attempts`.
+            ExitReason::IpDenied => (5, 60, None),
+            ExitReason::PeerNotResponding
+            | ExitReason::ModemHangup
+            | ExitReason::TerminatedByPeer
+            | ExitReason::Other(_) => (5, 30, None),
+        };
+        RetryPolicy {
+            delay: Duration::from_secs(delay),
+            max_delay: Duration::from_secs(max_delay),
+            max_attempts,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exited(code: i32) -> ExitStatus {
+        ExitStatus::from_raw(code << 8)
+    }
+
+    #[test]
+    fn classifies_pppd_lines() {
+        let cases = [
+            ("Timeout waiting for PADO packets", Some(ExitReason::NoPado)),
+            (
+                "Unable to complete PPPoE Discovery",
+                Some(ExitReason::NoPado),
+            ),
+            ("PAP authentication failed", Some(ExitReason::AuthFailed)),
+            (
+                "No response to 3 echo-requests",
+                Some(ExitReason::PeerNotResponding),
+            ),
+            (
+                "LCP: timeout sending Config-Requests",
+                Some(ExitReason::LcpTimeout),
+            ),
+            (
+                "LCP terminated by peer (Session terminated)",
+                Some(ExitReason::TerminatedByPeer),
+            ),
+            ("Modem hangup", Some(ExitReason::ModemHangup)),
+            ("Terminating on signal 15", Some(ExitReason::Killed)),
+            ("Connection terminated.", None),
+            ("PAP authentication succeeded", None),
+        ];
+        for (line, reason) in cases {
+            assert_eq!(ExitReason::from_line(line), reason, "{}", line);
+        }
+    }
+
+    #[test]
+    fn classifies_pppd_exit_codes() {
+        let cases = [
+            (5, ExitReason::Killed),
+            (10, ExitReason::LcpTimeout),
+            (15, ExitReason::PeerNotResponding),
+            (16, ExitReason::ModemHangup),
+            (19, ExitReason::AuthFailed),
+            (8, ExitReason::Other("pppd exit code 8".to_string())),
+        ];
+        for (code, reason) in cases {
+            assert_eq!(
+                ExitReason::from_pppd(exited(code), None),
+                reason,
+                "{}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn prefers_hint_over_exit_code() {
+        assert_eq!(
+            ExitReason::from_pppd(exited(16), Some(ExitReason::NoPado)),
+            ExitReason::NoPado
+        );
+    }
+
+    #[test]
+    fn signal_means_killed() {
+        // A raw wait status below 0x80 is the number of the signal that ended it.
+        let status = ExitStatus::from_raw(9);
+        assert_eq!(
+            ExitReason::from_pppd(status, Some(ExitReason::AuthFailed)),
+            ExitReason::Killed
+        );
+    }
+
+    #[test]
+    fn classifies_native_errors() {
+        let cases = [
+            (NativeError::NoOffer("eth0".to_string()), ExitReason::NoPado),
+            (NativeError::NoConfirmation, ExitReason::NoPado),
+            (
+                NativeError::AuthFailed("bad login".to_string()),
+                ExitReason::AuthFailed,
+            ),
+            (NativeError::IpcpTimeout, ExitReason::LcpTimeout),
+            (
+                NativeError::PeerNotResponding,
+                ExitReason::PeerNotResponding,
+            ),
+            (NativeError::Padt, ExitReason::TerminatedByPeer),
+            (
+                NativeError::NoAddress,
+                ExitReason::Other("Peer did not assign an IP address".to_string()),
+            ),
+        ];
+        for (error, reason) in cases {
+            assert_eq!(ExitReason::from_native(&error), reason, "{}", error);
+        }
+    }
+
+    #[test]
+    fn delay_doubles_up_to_max() {
+        let policy = ExitReason::NoPado.retry_policy();
+        let delays: Vec<u64> = (1..=7).map(|n| policy.delay(n).as_secs()).collect();
+        assert_eq!(delays, [10, 20, 40, 80, 160, 300, 300]);
+        assert_eq!(policy.delay(0), Duration::from_secs(10));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn delay_saturates_without_max() {
+        let policy = RetryPolicy {
+            delay: Duration::MAX,
+            max_delay: Duration::MAX,
+            max_attempts: None,
+        };
+        assert_eq!(policy.delay(u32::MAX), Duration::MAX);
+    }
+}
@@ -0,0 +1,163 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// An IP a session held and for how long.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeaseRecord {
+    pub interface: String,
+    pub ip: String,
+    pub connected_at: DateTime<Utc>,
+    pub disconnected_at: Option<DateTime<Utc>>,
+    /// Counters of the ppp interface, which pppd creates anew for every lease.
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl LeaseRecord {
+    pub fn active_at(&self, at: DateTime<Utc>) -> bool {
+        self.connected_at <= at && self.disconnected_at.is_none_or(|end| end >= at)
+    }
+}
+
+/// Append-only JSON lines log of every lease.
+///
+/// A lease is written when it starts and again when it ends; the later line wins.
+pub struct IpHistory {
+    path: PathBuf,
+    open: Mutex<HashMap<String, LeaseRecord>>,
+}
+
+impl IpHistory {
+    /// Opens the log, closing leases left open by an unclean shutdown at the current time.
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let history = Self {
+            path,
+            open: Mutex::new(HashMap::new()),
+        };
+
+        let now = Utc::now();
+        let dangling: Vec<LeaseRecord> = history
+            .load()
+            .await?
+            .into_iter()
+            .filter(|record| record.disconnected_at.is_none())
+            .collect();
+        for mut record in dangling {
+            warn!(
+                "{}: Lease of {} was never closed, ending it now",
+                record.interface, record.ip
+            );
+            record.disconnected_at = Some(now);
+            history.append(&record).await?;
+        }
+        info!("IP history stored in {}", history.path.display());
+        Ok(history)
+    }
+
+    async fn append(&self, record: &LeaseRecord) -> Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .with_context(|| format!("Failed to open {}", self.path.display()))?;
+        file.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// All leases, oldest first, with later lines replacing earlier ones for the same lease.
+    async fn load(&self) -> Result<Vec<LeaseRecord>> {
+        let content = match fs::read_to_string(&self.path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", self.path.display()));
+            }
+        };
+        let mut leases = BTreeMap::new();
+        for (n, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<LeaseRecord>(line) {
+                Ok(record) => {
+                    let key = (record.connected_at, record.interface.clone());
+                    leases.insert(key, record);
+                }
+                Err(e) => warn!(
+                    "{}:{}: Skipping bad record: {}",
+                    self.path.display(),
+                    n + 1,
+                    e
+                ),
+            }
+        }
+        Ok(leases.into_values().collect())
+    }
+
+    /// Records the current lease of a session, closing the previous one if it changed.
+    pub async fn observe(
+        &self,
+        interface: &str,
+        lease: Option<(&str, DateTime<Utc>)>,
+        bytes_sent: u64,
+        bytes_received: u64,
+    ) -> Result<()> {
+        let mut open = self.open.lock().await;
+        if let Some(current) = open.get(interface)
+            && lease == Some((current.ip.as_str(), current.connected_at))
+        {
+            return Ok(());
+        }
+        if let Some(mut ended) = open.remove(interface) {
+            ended.disconnected_at = Some(Utc::now());
+            ended.bytes_sent = bytes_sent;
+            ended.bytes_received = bytes_received;
+            self.append(&ended).await?;
+        }
+        if let Some((ip, connected_at)) = lease {
+            let record = LeaseRecord {
+                interface: interface.to_string(),
+                ip: ip.to_string(),
+                connected_at,
+                disconnected_at: None,
+                bytes_sent: 0,
+                bytes_received: 0,
+            };
+            self.append(&record).await?;
+            open.insert(interface.to_string(), record);
+        }
+        Ok(())
+    }
+
+    /// Leases matching `ip` and active at `at`, newest first.
+    pub async fn query(
+        &self,
+        ip: Option<&str>,
+        at: Option<DateTime<Utc>>,
+        limit: usize,
+    ) -> Result<Vec<LeaseRecord>> {
+        let mut leases = self.load().await?;
+        leases.retain(|record| {
+            ip.is_none_or(|ip| record.ip == ip) && at.is_none_or(|at| record.active_at(at))
+        });
+        leases.reverse();
+        leases.truncate(limit);
+        Ok(leases)
+    }
+}
@@ -0,0 +1,274 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::pppoe::exit::ExitReason;
+
+/// What a session negotiated besides its IP, as far as the backend reports it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Lease {
+    pub remote_ip: Option<Ipv4Addr>,
+    pub primary_dns: Option<Ipv4Addr>,
+    pub secondary_dns: Option<Ipv4Addr>,
+    /// Largest frame the peer takes, which is the interface MTU.
+    pub mtu: Option<u16>,
+    /// Largest frame we take.
+    pub mru: Option<u16>,
+    /// `PAP` or `CHAP`, as pppd names them.
+    pub auth_method: Option<String>,
+    pub session_id: Option<u16>,
+    /// Name of the access concentrator that accepted the session.
+    pub ac_name: Option<String>,
+    pub ipv6_link_local: Option<Ipv6Addr>,
+    /// Why the session ended, once it has.
+    pub termination_reason: Option<String>,
+}
+
+/// What a pppd log line was about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogLine {
+    /// `local  IP address`, the first line pppd prints once IPCP is up.
+    LocalIp,
+    /// One of the lines following it: remote IP, DNS servers or link-local addresses.
+    Address,
+    Other,
+}
+
+/// Builds a [`Lease`] from pppd's output, one line at a time.
+///
+/// MTU and MRU come from the `sent`/`rcvd` LCP lines pppd only prints with `debug`; the
+/// session ID and AC name from the lines the rp-pppoe plugin prints, the latter only
+/// with `rp_pppoe_verbose`.
+#[derive(Debug, Default)]
+pub struct PppdLog {
+    pub local_ip: Option<Ipv4Addr>,
+    pub lease: Lease,
+    /// The first line that tells why the connection failed, such as a discovery timeout.
+    pub exit_reason: Option<ExitReason>,
+}
+
+/// pppd's lines for a link going down. The first one seen is kept: those following it,
+/// like `Connection terminated.`, say less.
+const TERMINATION_LINES: &[&str] = &[
+    "LCP terminated by peer",
+    "No response to ",
+    "Modem hangup",
+    "Serial link appears to be disconnected",
+    "authentication failed",
+    "timeout sending Config-Requests",
+    "Terminating on signal",
+    "Connection terminated",
+];
+
+impl PppdLog {
+    pub fn feed(&mut self, line: &str) -> LogLine {
+        let line = line.trim();
+        if let Some(ip) = value_after(line, "local  IP address") {
+            self.local_ip = ip.parse().ok();
+            return LogLine::LocalIp;
+        }
+        if let Some(ip) = value_after(line, "remote IP address") {
+            self.lease.remote_ip = ip.parse().ok();
+            return LogLine::Address;
+        }
+        if let Some(ip) = value_after(line, "primary   DNS address") {
+            self.lease.primary_dns = ip.parse().ok();
+            return LogLine::Address;
+        }
+        if let Some(ip) = value_after(line, "secondary DNS address") {
+            self.lease.secondary_dns = ip.parse().ok();
+            return LogLine::Address;
+        }
+        if let Some(ip) = value_after(line, "local  LL address") {
+            self.lease.ipv6_link_local = ip.parse().ok();
+            return LogLine::Address;
+        }
+        if value_after(line, "remote LL address").is_some() {
+            return LogLine::Address;
+        }
+
+        if self.exit_reason.is_none() {
+            self.exit_reason = ExitReason::from_line(line);
+        }
+        if let Some((method, _)) = line.split_once(" authentication succeeded") {
+            self.lease.auth_method = Some(method.to_string());
+        } else if let Some(id) = value_after(line, "PPP session is") {
+            self.lease.session_id = id.parse().ok();
+        } else if let Some(name) = line
+            .strip_prefix("Access-Concentrator:")
+            .or_else(|| line.strip_prefix("AC-Name:"))
+        {
+            self.lease.ac_name = Some(name.trim().to_string());
+        } else if let Some(options) = line.strip_prefix("sent [LCP ConfAck") {
+            // Acking the peer's MRU caps what we send.
+            self.lease.mtu = option_value(options, "mru").or(self.lease.mtu);
+        } else if let Some(options) = line.strip_prefix("rcvd [LCP ConfAck") {
+            self.lease.mru = option_value(options, "mru").or(self.lease.mru);
+        } else if self.lease.termination_reason.is_none()
+            && TERMINATION_LINES.iter().any(|known| line.contains(known))
+        {
+            let reason = line.trim_end_matches('.');
+            self.lease.termination_reason = Some(reason.to_string());
+        }
+        LogLine::Other
+    }
+}
+
+/// The first word after `prefix`, e.g. the address in `remote IP address 10.0.0.1`.
+fn value_after<'a>(line: &'a str, prefix: &str) -> Option<&'a str> {
+    line.split_once(prefix)?.1.split_whitespace().next()
+}
+
+/// The value of `<name value>` in pppd's dump of an LCP packet.
+fn option_value(options: &str, name: &str) -> Option<u16> {
+    options
+        .split('<')
+        .filter_map(|option| option.split('>').next())
+        .find_map(|option| option.strip_prefix(name)?.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What pppd 2.4.9 prints on stdout when dialed the way the client does it:
+    /// `plugin rp-pppoe.so eth0 rp_pppoe_verbose 1 debug ... ifname ppp0`.
+    const SESSION: &[&str] = &[
+        "Plugin rp-pppoe.so loaded.",
+        "RP-PPPoE plugin version 3.8p compiled against pppd 2.4.9",
+        "Access-Concentrator: BRAS-FRA-01",
+        "Got a cookie: 8f 3c 11 de 55 a0 42 07",
+        "AC-Ethernet-Address: 00:1a:2b:3c:4d:5e",
+        "--------------------------------------------------",
+        "PPP session is 4711",
+        "Connected to 00:1A:2B:3C:4D:5E via interface eth0",
+        "using channel 12",
+        "Using interface ppp0",
+        "Connect: ppp0 <--> eth0",
+        "sent [LCP ConfReq id=0x1 <mru 1492> <magic 0x5f3e2d1c>]",
+        "rcvd [LCP ConfReq id=0x7 <mru 1480> <auth pap> <magic 0x1a2b3c4d>]",
+        "sent [LCP ConfAck id=0x7 <mru 1480> <auth pap> <magic 0x1a2b3c4d>]",
+        "rcvd [LCP ConfAck id=0x1 <mru 1492> <magic 0x5f3e2d1c>]",
+        "sent [PAP AuthReq id=0x1 user=\"user@isp\" password=<hidden>]",
+        "rcvd [PAP AuthAck id=0x1 \"\"]",
+        "PAP authentication succeeded",
+        "sent [IPCP ConfReq id=0x1 <addr 0.0.0.0> <ms-dns1 0.0.0.0> <ms-dns3 0.0.0.0>]",
+        "rcvd [IPCP ConfReq id=0x3 <addr 100.64.0.1>]",
+        "sent [IPCP ConfAck id=0x3 <addr 100.64.0.1>]",
+        "rcvd [IPCP ConfNak id=0x1 <addr 100.64.12.34> <ms-dns1 194.25.2.129> <ms-dns3 194.25.2.130>]",
+        "sent [IPCP ConfReq id=0x2 <addr 100.64.12.34> <ms-dns1 194.25.2.129> <ms-dns3 194.25.2.130>]",
+        "rcvd [IPCP ConfAck id=0x2 <addr 100.64.12.34> <ms-dns1 194.25.2.129> <ms-dns3 194.25.2.130>]",
+    ];
+
+    const IPCP_UP: &[&str] = &[
+        "local  IP address 100.64.12.34",
+        "remote IP address 100.64.0.1",
+        "primary   DNS address 194.25.2.129",
+        "secondary DNS address 194.25.2.130",
+    ];
+
+    fn feed_all(log: &mut PppdLog, lines: &[&str]) -> Vec<LogLine> {
+        lines.iter().map(|line| log.feed(line)).collect()
+    }
+
+    #[test]
+    fn reads_lease_from_session_output() {
+        let mut log = PppdLog::default();
+        feed_all(&mut log, SESSION);
+        feed_all(&mut log, IPCP_UP);
+
+        assert_eq!(log.local_ip, Some(Ipv4Addr::new(100, 64, 12, 34)));
+        assert_eq!(
+            log.lease,
+            Lease {
+                remote_ip: Some(Ipv4Addr::new(100, 64, 0, 1)),
+                primary_dns: Some(Ipv4Addr::new(194, 25, 2, 129)),
+                secondary_dns: Some(Ipv4Addr::new(194, 25, 2, 130)),
+                mtu: Some(1480),
+                mru: Some(1492),
+                auth_method: Some("PAP".to_string()),
+                session_id: Some(4711),
+                ac_name: Some("BRAS-FRA-01".to_string()),
+                ipv6_link_local: None,
+                termination_reason: None,
+            }
+        );
+        assert_eq!(log.exit_reason, None);
+    }
+
+    #[test]
+    fn classifies_address_lines() {
+        let mut log = PppdLog::default();
+        assert!(
+            feed_all(&mut log, SESSION)
+                .iter()
+                .all(|line| *line == LogLine::Other)
+        );
+        assert_eq!(
+            feed_all(&mut log, IPCP_UP),
+            [
+                LogLine::LocalIp,
+                LogLine::Address,
+                LogLine::Address,
+                LogLine::Address
+            ]
+        );
+        assert_eq!(
+            log.feed("Script /etc/ppp/ip-up started (pid 2113)"),
+            LogLine::Other
+        );
+    }
+
+    #[test]
+    fn keeps_first_termination_line() {
+        let mut log = PppdLog::default();
+        feed_all(
+            &mut log,
+            &[
+                "rcvd [LCP TermReq id=0x2 \"Session terminated\"]",
+                "LCP terminated by peer (Session terminated)",
+                "Connect time 61.2 minutes.",
+                "Sent 1523812 bytes, received 80412393 bytes.",
+                "sent [LCP TermAck id=0x2]",
+                "Modem hangup",
+                "Connection terminated.",
+            ],
+        );
+        assert_eq!(
+            log.lease.termination_reason.as_deref(),
+            Some("LCP terminated by peer (Session terminated)")
+        );
+        assert_eq!(log.exit_reason, Some(ExitReason::TerminatedByPeer));
+
+        let mut log = PppdLog::default();
+        log.feed("Connection terminated.");
+        assert_eq!(
+            log.lease.termination_reason.as_deref(),
+            Some("Connection terminated")
+        );
+    }
+
+    #[test]
+    fn reads_discovery_failure() {
+        let mut log = PppdLog::default();
+        feed_all(
+            &mut log,
+            &[
+                "Plugin rp-pppoe.so loaded.",
+                "RP-PPPoE plugin version 3.8p compiled against pppd 2.4.9",
+                "Timeout waiting for PADO packets",
+                "Unable to complete PPPoE Discovery",
+            ],
+        );
+        assert_eq!(log.exit_reason, Some(ExitReason::NoPado));
+        assert_eq!(log.lease, Lease::default());
+    }
+
+    #[test]
+    fn ignores_unparsable_values() {
+        let mut log = PppdLog::default();
+        assert_eq!(log.feed("local  IP address ???"), LogLine::LocalIp);
+        log.feed("PPP session is unknown");
+        log.feed("rcvd [LCP ConfAck id=0x1 <magic 0x5f3e2d1c>]");
+        assert_eq!(log.local_ip, None);
+        assert_eq!(log.lease, Lease::default());
+    }
+}
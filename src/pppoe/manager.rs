@@ -1,22 +1,37 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use chrono::{DateTime, Utc};
 
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use std::collections::BTreeMap;
-use std::sync::Arc;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
 use sysinfo::Networks;
 use tokio::process::Command;
-use tokio::sync::{Mutex, mpsc};
+use tokio::sync::{Mutex, broadcast, mpsc, oneshot};
 use tokio::task::JoinHandle;
 use tokio::time::{self, Duration};
 
-use crate::core::config::{IpRotationConfig, time_string_to_sec};
+use crate::core::config::{IpRotationConfig, IpVerdict, PppoeBackend, RotationMode, SessionConfig};
+use crate::core::schedule::{RotationSchedule, ScheduleTimezone, format_in};
+use crate::network::allocator::{RouteAllocator, RouteRepair};
+use crate::network::netlink::DefaultRoute;
+use crate::network::route::session_tun;
 use crate::pppoe::client::PPPoEClient;
+use crate::pppoe::exit::ExitReason;
+use crate::pppoe::history::IpHistory;
+use crate::pppoe::lease::Lease;
+use crate::pppoe::session_log::SessionLogs;
 
 #[derive(Debug, Clone, Default)]
 pub struct ConnectionInfo {
+    /// Name of the account the session dials with.
+    pub account: String,
+    /// NIC or VLAN the session dials over.
+    pub uplink: String,
     pub connected_at: Option<DateTime<Utc>>,
     pub local_ip: Option<String>,
+    /// Everything else negotiated for the current or, once down, the last connection.
+    pub lease: Lease,
     pub bytes_sent: u64,
     pub bytes_received: u64,
     pub packets_sent: u64,
@@ -27,6 +42,99 @@ pub struct ConnectionInfo {
     pub is_healthy: bool,
     pub last_health_check: Option<DateTime<Utc>>,
     pub consecutive_failures: u32,
+    pub active_connections: u64,
+    pub rotating: bool,
+    /// A rotation is waiting to judge the next lease, so only it clears `rotating`: a
+    /// duplicate IP must not be selectable before the rotation rerolls it.
+    pub rotation_pending: bool,
+    pub next_rotation_at: Option<DateTime<Utc>>,
+    pub last_rotation: Option<RotationOutcome>,
+    /// Connects it took to get the current IP past the deny/prefer lists.
+    pub lease_attempts: u32,
+    /// Rejected IPs since the last accepted one.
+    pub pending_attempts: u32,
+    pub rejected_ips: u64,
+    /// Why the client stopped reconnecting, until it is told to connect again.
+    pub gave_up: Option<ExitReason>,
+}
+
+impl ConnectionInfo {
+    /// Whether the session can carry proxy traffic: connected, not rotating and not
+    /// failing health checks.
+    pub fn is_available(&self) -> bool {
+        self.local_ip.is_some() && !self.rotating && self.consecutive_failures == 0
+    }
+}
+
+/// Result of reconnecting a session to get a different IP.
+#[derive(Debug, Clone)]
+pub struct RotationOutcome {
+    pub interface: String,
+    pub previous_ip: Option<String>,
+    pub new_ip: Option<String>,
+    pub attempts: u32,
+    pub finished_at: DateTime<Utc>,
+}
+
+impl RotationOutcome {
+    /// Whether the session ended up with an IP it did not have before.
+    pub fn changed(&self) -> bool {
+        self.new_ip.is_some() && self.new_ip != self.previous_ip
+    }
+}
+
+impl std::fmt::Display for RotationOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} -> {} after {} attempt(s)",
+            self.interface,
+            self.previous_ip.as_deref().unwrap_or("none"),
+            self.new_ip.as_deref().unwrap_or("none"),
+            self.attempts
+        )
+    }
+}
+
+/// What [`PPPoEManager::resize`] did to the running clients.
+#[derive(Debug, Default)]
+pub struct ClientChanges {
+    pub started: Vec<String>,
+    pub stopped: Vec<String>,
+    /// Kept running but redialed with other credentials or over another uplink.
+    pub switched: Vec<String>,
+}
+
+/// A connection counted in [`ConnectionInfo::active_connections`] while this lives.
+pub struct ConnectionGuard {
+    manager: Arc<PPPoEManager>,
+    interface: String,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let manager = Arc::clone(&self.manager);
+        let interface = std::mem::take(&mut self.interface);
+        tokio::spawn(async move { manager.connection_closed(&interface).await });
+    }
+}
+
+/// A session that stopped reconnecting on its own and needs someone to look at it.
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub interface: String,
+    pub reason: ExitReason,
+    pub at: DateTime<Utc>,
+}
+
+impl std::fmt::Display for Alert {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: gave up reconnecting ({})",
+            self.interface, self.reason
+        )
+    }
 }
 
 #[derive(Debug)]
@@ -34,6 +142,20 @@ pub enum ClientCommand {
     Connect,
     Disconnect,
     Reconnect,
+    /// Hang up and dial again after `after`, e.g. when the IP got rejected.
+    Redial {
+        after: Duration,
+    },
+    /// Dial with other credentials, over another uplink or with another backend from the
+    /// next connect on.
+    Configure {
+        username: String,
+        password: String,
+        uplink: String,
+        backend: PppoeBackend,
+    },
+    /// Disconnect and end the client task, for sessions removed by a reload.
+    Stop,
 }
 
 #[derive(Debug)]
@@ -42,62 +164,196 @@ pub enum PpmsEvent {
         interface: String,
         local_ip: Option<String>,
         connected_at: Option<DateTime<Utc>>,
+        lease: Lease,
     },
     Disconnected {
         interface: String,
+        reason: Option<String>,
+    },
+    /// The client failed the same way too often in a row and stopped reconnecting.
+    GaveUp {
+        interface: String,
+        reason: ExitReason,
+    },
+    Stopped {
+        interface: String,
     },
+    RotationCompleted(RotationOutcome),
+    RouteRepaired(RouteRepair),
+}
+
+/// Alerts kept for receivers that are busy posting the previous ones.
+const ALERT_BACKLOG: usize = 32;
+
+/// A running rotation timer. Dropping it stops the timer once any rotation it started
+/// has finished, so sessions are never left disconnected halfway through one.
+struct ScheduleTimer {
+    interfaces: Vec<String>,
+    schedule: RotationSchedule,
+    _stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
 }
 
 pub struct PPPoEManager {
     data: Arc<Mutex<BTreeMap<String, ConnectionInfo>>>,
     client_controls: Arc<Mutex<BTreeMap<String, mpsc::Sender<ClientCommand>>>>,
-    config: IpRotationConfig,
+    /// How each running client dials.
+    client_sessions: Mutex<BTreeMap<String, SessionConfig>>,
+    config: RwLock<Arc<IpRotationConfig>>,
+    history: Arc<IpHistory>,
+    logs: SessionLogs,
+    routes: Arc<RouteAllocator>,
     stats_task: Mutex<Option<JoinHandle<()>>>,
     health_check_task: Mutex<Option<JoinHandle<()>>>,
+    reconcile_task: Mutex<Option<JoinHandle<()>>>,
+    schedule_timers: Mutex<Vec<ScheduleTimer>>,
     event_receiver: Mutex<Option<mpsc::Receiver<PpmsEvent>>>,
+    event_sender: Mutex<Option<mpsc::Sender<PpmsEvent>>>,
+    alerts: broadcast::Sender<Alert>,
 }
 
 impl PPPoEManager {
-    pub fn new(config: IpRotationConfig) -> Arc<Self> {
+    pub fn new(
+        config: IpRotationConfig,
+        history: Arc<IpHistory>,
+        logs: SessionLogs,
+        routes: Arc<RouteAllocator>,
+    ) -> Arc<Self> {
         info!("IP Rotation Config: {:?}", config);
 
         Arc::new(Self {
             data: Arc::new(Mutex::new(BTreeMap::new())),
             client_controls: Arc::new(Mutex::new(BTreeMap::new())),
-            config,
+            client_sessions: Mutex::new(BTreeMap::new()),
+            config: RwLock::new(Arc::new(config)),
+            history,
+            logs,
+            routes,
             stats_task: Mutex::new(None),
             health_check_task: Mutex::new(None),
+            reconcile_task: Mutex::new(None),
+            schedule_timers: Mutex::new(Vec::new()),
             event_receiver: Mutex::new(None),
+            event_sender: Mutex::new(None),
+            alerts: broadcast::channel(ALERT_BACKLOG).0,
         })
     }
 
+    /// The current settings; a reload swaps them, so don't hold on to the result for long.
+    pub fn config(&self) -> Arc<IpRotationConfig> {
+        Arc::clone(&self.config.read().unwrap())
+    }
+
+    pub fn timezone(&self) -> ScheduleTimezone {
+        self.config().timezone
+    }
+
+    pub fn routes(&self) -> &RouteAllocator {
+        &self.routes
+    }
+
+    /// Alerts raised from now on; a receiver that falls behind skips the oldest.
+    pub fn alerts(&self) -> broadcast::Receiver<Alert> {
+        self.alerts.subscribe()
+    }
+
+    pub fn history(&self) -> &IpHistory {
+        &self.history
+    }
+
+    pub fn logs(&self) -> &SessionLogs {
+        &self.logs
+    }
+
     pub async fn set_event_receiver(&self, receiver: mpsc::Receiver<PpmsEvent>) {
         *self.event_receiver.lock().await = Some(receiver);
     }
 
     pub async fn start_clients(
         &self,
-        username: String,
-        password: String,
-        count: u16,
+        sessions: &[SessionConfig],
         event_sender: mpsc::Sender<PpmsEvent>,
     ) {
+        *self.event_sender.lock().await = Some(event_sender);
+        self.resize(sessions).await;
+    }
+
+    /// Starts, stops or reconfigures clients to match `sessions`. Sessions whose
+    /// credentials and uplink did not change are left alone. Returns the interfaces
+    /// started, stopped and redialed with new settings.
+    pub async fn resize(&self, sessions: &[SessionConfig]) -> ClientChanges {
+        let mut changes = ClientChanges::default();
+        let Some(event_sender) = self.event_sender.lock().await.clone() else {
+            error!("Cannot start clients before the event channel is set");
+            return changes;
+        };
         let mut controls = self.client_controls.lock().await;
-        for i in 0..count {
-            let interface = format!("ppp{}", i);
-            let (cmd_tx, cmd_rx) = mpsc::channel(32);
-
-            let client = PPPoEClient::new(
-                username.clone(),
-                password.clone(),
-                interface.clone(),
-                event_sender.clone(),
-                cmd_rx,
-            );
+        let mut client_sessions = self.client_sessions.lock().await;
 
-            tokio::spawn(client.run());
-            controls.insert(interface, cmd_tx);
+        for session in sessions {
+            let interface = &session.interface;
+            let Some(tx) = controls.get(interface) else {
+                let (cmd_tx, cmd_rx) = mpsc::channel(32);
+                let client = PPPoEClient::new(
+                    session,
+                    event_sender.clone(),
+                    cmd_rx,
+                    self.logs.get(interface),
+                );
+                tokio::spawn(client.run());
+                controls.insert(interface.clone(), cmd_tx);
+                changes.started.push(interface.clone());
+                continue;
+            };
+            if client_sessions.get(interface).is_some_and(|current| {
+                current.username == session.username
+                    && current.password == session.password
+                    && current.uplink == session.uplink
+                    && current.backend == session.backend
+            }) {
+                continue;
+            }
+            let configure = ClientCommand::Configure {
+                username: session.username.clone(),
+                password: session.password.clone(),
+                uplink: session.uplink.clone(),
+                backend: session.backend,
+            };
+            // New credentials or a new uplink may well fix what it gave up on.
+            if let Some(info) = self.data.lock().await.get_mut(interface) {
+                info.gave_up = None;
+            }
+            if let Err(e) = tx.send(configure).await {
+                error!("Failed to reconfigure {}: {}", interface, e);
+            } else if let Err(e) = tx.send(ClientCommand::Reconnect).await {
+                error!("Failed to send Reconnect to {}: {}", interface, e);
+            }
+            changes.switched.push(interface.clone());
         }
+
+        let stopped: Vec<String> = controls
+            .keys()
+            .filter(|interface| !sessions.iter().any(|s| &s.interface == *interface))
+            .cloned()
+            .collect();
+        for interface in stopped {
+            client_sessions.remove(&interface);
+            if let Some(tx) = controls.remove(&interface)
+                && let Err(e) = tx.send(ClientCommand::Stop).await
+            {
+                error!("Failed to send Stop to {}: {}", interface, e);
+            }
+            changes.stopped.push(interface);
+        }
+
+        let mut data = self.data.lock().await;
+        for session in sessions {
+            let info = data.entry(session.interface.clone()).or_default();
+            info.account = session.account.clone();
+            info.uplink = session.uplink.clone();
+            client_sessions.insert(session.interface.clone(), session.clone());
+        }
+        changes
     }
 
     pub async fn start_stats_task(manager: Arc<Self>) {
@@ -128,22 +384,51 @@ impl PPPoEManager {
         *manager.stats_task.lock().await = Some(task);
     }
 
+    /// Periodically puts back rules and routes that disappeared from the kernel.
+    pub async fn start_reconcile_task(manager: Arc<Self>) {
+        let interval = manager.routes.reconcile_interval();
+        if interval.is_zero() {
+            info!("Route reconciliation is disabled");
+            return;
+        }
+
+        let manager_clone = Arc::clone(&manager);
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let repairs = match manager_clone.routes.reconcile().await {
+                    Ok(repairs) => repairs,
+                    Err(e) => {
+                        error!("Route reconciliation failed: {}", e);
+                        continue;
+                    }
+                };
+                if let Some(sender) = manager_clone.event_sender.lock().await.as_ref() {
+                    for repair in repairs {
+                        let _ = sender.send(PpmsEvent::RouteRepaired(repair)).await;
+                    }
+                }
+            }
+        });
+        *manager.reconcile_task.lock().await = Some(task);
+    }
+
     pub async fn start_health_check_task(manager: Arc<Self>) {
-        if !manager.config.health_check_enabled {
+        if !manager.config().health_check_enabled {
             info!("Health check is disabled");
             return;
         }
 
         info!(
             "Starting health check task (interval: {}s, threshold: {}, target: {})",
-            manager.config.health_check_interval_secs,
-            manager.config.health_check_failure_threshold,
-            manager.config.health_check_target
+            manager.config().health_check_interval_secs,
+            manager.config().health_check_failure_threshold,
+            manager.config().health_check_target
         );
 
         let manager_clone = Arc::clone(&manager);
         let task = tokio::spawn(async move {
-            let interval = Duration::from_secs(manager_clone.config.health_check_interval_secs);
+            let interval = Duration::from_secs(manager_clone.config().health_check_interval_secs);
             loop {
                 tokio::time::sleep(interval).await;
 
@@ -172,7 +457,7 @@ impl PPPoEManager {
     }
 
     pub async fn check_health(&self, interface: &str) -> bool {
-        let target = &self.config.health_check_target;
+        let target = &self.config().health_check_target;
 
         debug!(
             "Performing health check for {} (ping {})",
@@ -217,16 +502,17 @@ impl PPPoEManager {
                     "{}: consecutive failures = {}/{}",
                     interface,
                     info.consecutive_failures,
-                    self.config.health_check_failure_threshold
+                    self.config().health_check_failure_threshold
                 );
 
-                if info.consecutive_failures >= self.config.health_check_failure_threshold {
+                if info.consecutive_failures >= self.config().health_check_failure_threshold {
                     error!(
                         "{}: Health check failed {} times, triggering reconnect",
                         interface, info.consecutive_failures
                     );
                     drop(data);
-                    if let Err(e) = self.reconnect_client(interface).await {
+                    // Don't wait for the new lease here, it would stall checks of other sessions.
+                    if let Err(e) = self.send_command(interface, ClientCommand::Reconnect).await {
                         error!("Failed to reconnect {}: {}", interface, e);
                     }
                 }
@@ -248,34 +534,102 @@ impl PPPoEManager {
         if let Some(ip) = local_ip.clone() {
             info!("{}: {}", interface, ip);
         }
-        // Robust interface index parsing
-        let idx: u32 = interface.trim_start_matches("ppp").parse().unwrap_or(0);
+        if local_ip.is_none() {
+            self.routes
+                .forget_default_route(&session_tun(interface))
+                .await;
+        }
+        if let Some(ip) = &local_ip {
+            let filter = &self.config().ip_filter;
+            let verdict = filter.verdict(ip);
+            let reject = match verdict {
+                IpVerdict::Accept => false,
+                IpVerdict::Denied => true,
+                IpVerdict::NotPreferred => info.pending_attempts + 1 < filter.max_attempts,
+            };
+            if reject {
+                info.pending_attempts += 1;
+                info.rejected_ips += 1;
+                let attempts = info.pending_attempts;
+                // Only denied IPs can run out of attempts; otherwise the last one is kept.
+                let give_up = verdict == IpVerdict::Denied && attempts >= filter.max_attempts;
+                if give_up {
+                    info.pending_attempts = 0;
+                }
+                // Keep the rejected IP out of proxy selection and rotation waits.
+                info.local_ip = None;
+                info.connected_at = None;
+                drop(data);
+                self.routes
+                    .forget_default_route(&session_tun(interface))
+                    .await;
 
-        if let Err(e) = self.add_default_route(interface, 101 + idx).await {
-            error!("Failed to add default route for {}: {}", interface, e);
+                if give_up {
+                    warn!(
+                        "{}: {} is {}, no allowed IP after {} attempts",
+                        interface, ip, verdict, attempts
+                    );
+                    if let Err(e) = self
+                        .send_command(interface, ClientCommand::Disconnect)
+                        .await
+                    {
+                        error!("Failed to disconnect {}: {}", interface, e);
+                    }
+                    self.gave_up(interface, ExitReason::IpDenied).await;
+                    return;
+                }
+                let after = ExitReason::IpDenied.retry_policy().delay(attempts);
+                warn!(
+                    "{}: {} is {}, reconnecting in {}s (attempt {}/{})",
+                    interface,
+                    ip,
+                    verdict,
+                    after.as_secs(),
+                    attempts,
+                    filter.max_attempts
+                );
+                if let Err(e) = self
+                    .send_command(interface, ClientCommand::Redial { after })
+                    .await
+                {
+                    error!("Failed to reconnect {}: {}", interface, e);
+                }
+                return;
+            }
+            if verdict == IpVerdict::NotPreferred {
+                warn!(
+                    "{}: Keeping {} after {} attempts without a preferred IP",
+                    interface,
+                    ip,
+                    info.pending_attempts + 1
+                );
+            }
+            info.lease_attempts = info.pending_attempts + 1;
+            info.pending_attempts = 0;
+            if !info.rotation_pending {
+                info.rotating = false;
+            }
+            if let Err(e) = self.add_default_route(interface).await {
+                error!("Failed to add default route for {}: {}", interface, e);
+            }
         }
         info.local_ip = local_ip;
         info.connected_at = connected_at;
     }
 
-    pub async fn add_default_route(&self, interface: &str, table_id: u32) -> Result<()> {
-        Command::new("ip")
-            .args([
-                "route",
-                "add",
-                "default",
-                "dev",
-                interface,
-                "table",
-                &table_id.to_string(),
-            ])
-            .output()
+    pub async fn add_default_route(&self, interface: &str) -> Result<()> {
+        let tun = session_tun(interface);
+        let allocation = self
+            .routes
+            .get(&tun)
             .await
-            .map_err(|e| {
-                error!("Failed to add default route: {}", e);
-                e
-            })?;
-        Ok(())
+            .ok_or_else(|| anyhow!("No routing table allocated for {}", tun))?;
+        let route = DefaultRoute {
+            table: allocation.table,
+            oif: interface.to_string(),
+            gateway: None,
+        };
+        self.routes.install_default_route(&tun, route).await
     }
 
     pub async fn stop_all(&self) {
@@ -288,6 +642,40 @@ impl PPPoEManager {
         debug!("Sent Disconnect command to all clients");
     }
 
+    /// Stops the background tasks and disconnects every session, waiting up to `timeout`
+    /// for pppd to go down so its routes are gone before teardown.
+    pub async fn shutdown(&self, timeout: Duration) {
+        for task in [
+            &self.stats_task,
+            &self.health_check_task,
+            &self.reconcile_task,
+        ] {
+            if let Some(task) = task.lock().await.take() {
+                task.abort();
+            }
+        }
+        for timer in self.schedule_timers.lock().await.drain(..) {
+            timer.task.abort();
+        }
+        self.stop_all().await;
+
+        let deadline = time::Instant::now() + timeout;
+        while time::Instant::now() < deadline {
+            let connected = self
+                .data
+                .lock()
+                .await
+                .values()
+                .filter(|info| info.local_ip.is_some())
+                .count();
+            if connected == 0 {
+                return;
+            }
+            time::sleep(Duration::from_millis(200)).await;
+        }
+        warn!("Some sessions did not disconnect within {:?}", timeout);
+    }
+
     pub async fn start_all(&self) {
         let controls = self.client_controls.lock().await;
         for (interface, tx) in controls.iter() {
@@ -299,18 +687,29 @@ impl PPPoEManager {
         debug!("Sent Connect command to all clients");
     }
 
-    pub async fn reconnect_client(&self, interface: &str) -> Result<()> {
+    async fn send_command(&self, interface: &str, command: ClientCommand) -> Result<()> {
         let controls = self.client_controls.lock().await;
         if let Some(tx) = controls.get(interface) {
-            tx.send(ClientCommand::Reconnect)
+            tx.send(command)
                 .await
-                .map_err(|e| anyhow::anyhow!("Failed to send Reconnect: {}", e))?;
+                .map_err(|e| anyhow::anyhow!("Failed to send command: {}", e))?;
             Ok(())
         } else {
             Err(anyhow::anyhow!("Interface {} not found", interface))
         }
     }
 
+    /// Reconnects `interface` and rerolls until it gets an IP no session had before.
+    pub async fn reconnect_client(&self, interface: &str) -> Result<RotationOutcome> {
+        let previous_ip = self.mark_rotating(interface).await;
+        let started_at = Utc::now();
+        if let Err(e) = self.send_command(interface, ClientCommand::Reconnect).await {
+            self.finish_rotation(interface).await;
+            return Err(e);
+        }
+        Ok(self.reroll(interface, previous_ip, started_at).await)
+    }
+
     pub async fn disconnect_client(&self, interface: &str) -> Result<()> {
         let controls = self.client_controls.lock().await;
         if let Some(tx) = controls.get(interface) {
@@ -324,6 +723,9 @@ impl PPPoEManager {
     }
 
     pub async fn connect_client(&self, interface: &str) -> Result<()> {
+        if let Some(info) = self.data.lock().await.get_mut(interface) {
+            info.gave_up = None;
+        }
         let controls = self.client_controls.lock().await;
         if let Some(tx) = controls.get(interface) {
             tx.send(ClientCommand::Connect)
@@ -335,37 +737,323 @@ impl PPPoEManager {
         }
     }
 
+    /// Counts a proxied connection through `interface` until the guard is dropped, however
+    /// its handler ends.
+    pub async fn connection_opened(self: &Arc<Self>, interface: &str) -> ConnectionGuard {
+        let mut data = self.data.lock().await;
+        if let Some(info) = data.get_mut(interface) {
+            info.active_connections += 1;
+        }
+        ConnectionGuard {
+            manager: Arc::clone(self),
+            interface: interface.to_string(),
+        }
+    }
+
+    async fn connection_closed(&self, interface: &str) {
+        let mut data = self.data.lock().await;
+        if let Some(info) = data.get_mut(interface) {
+            info.active_connections = info.active_connections.saturating_sub(1);
+        }
+    }
+
+    pub async fn local_ip(&self, interface: &str) -> Option<IpAddr> {
+        let data = self.data.lock().await;
+        data.get(interface)
+            .and_then(|info| info.local_ip.as_deref())
+            .and_then(|ip| ip.parse().ok())
+    }
+
     pub async fn get_all_stats(&self) -> BTreeMap<String, ConnectionInfo> {
         let data = self.data.lock().await;
         data.clone()
     }
 
-    pub async fn rotate_ips(&self) {
-        debug!("Starting IP rotation for all clients");
+    pub async fn rotate_ips(&self, interfaces: &[String]) {
+        // A timer replaced by a reload may still be rotating some of them, and those that
+        // gave up stay down until someone connects them again.
+        let interfaces: Vec<String> = {
+            let data = self.data.lock().await;
+            interfaces
+                .iter()
+                .filter(|interface| {
+                    !data
+                        .get(*interface)
+                        .is_some_and(|info| info.rotation_pending || info.gave_up.is_some())
+                })
+                .cloned()
+                .collect()
+        };
+        if interfaces.is_empty() {
+            return;
+        }
+        match self.config().mode {
+            RotationMode::All => self.rotate_all(&interfaces).await,
+            RotationMode::Rolling => self.rotate_rolling(&interfaces).await,
+        }
+    }
+
+    async fn rotate_all(&self, interfaces: &[String]) {
+        debug!("Starting IP rotation for {:?}", interfaces);
 
-        self.stop_all().await;
+        // Take the sessions out of proxy selection before pppd goes down.
+        let started_at = Utc::now();
+        let mut previous_ips = BTreeMap::new();
+        for interface in interfaces {
+            previous_ips.insert(interface.clone(), self.mark_rotating(interface).await);
+        }
+        for interface in interfaces {
+            if let Err(e) = self.disconnect_client(interface).await {
+                error!("Failed to disconnect {}: {}", interface, e);
+            }
+        }
 
         debug!(
             "Waiting {} seconds before reconnecting",
-            self.config.wait_seconds
+            self.config().wait_seconds
         );
-        time::sleep(Duration::from_secs(self.config.wait_seconds as u64)).await;
+        time::sleep(Duration::from_secs(self.config().wait_seconds as u64)).await;
 
-        self.start_all().await;
+        for interface in interfaces {
+            if let Err(e) = self.connect_client(interface).await {
+                error!("Failed to connect {}: {}", interface, e);
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+        debug!("Reconnection phase completed for {:?}", interfaces);
+
+        for interface in interfaces {
+            let previous = previous_ips.get(interface).cloned().flatten();
+            self.reroll(interface, previous, started_at).await;
+        }
 
-        debug!("Reconnection phase completed for all clients");
-        debug!("IP rotation completed for all clients");
+        debug!("IP rotation completed for {:?}", interfaces);
     }
 
-    fn calculate_next_rotation_seconds(&self) -> i64 {
-        if let Ok(interval) = self.config.rotation_time.parse::<i64>() {
-            return interval * 60;
+    async fn rotate_rolling(&self, interfaces: &[String]) {
+        // Never take the whole pool down at once, even with a large batch size.
+        let batch_size = self
+            .config()
+            .batch_size
+            .min(interfaces.len().saturating_sub(1))
+            .max(1);
+        let timeout = Duration::from_secs(self.config().reconnect_timeout_secs);
+
+        debug!(
+            "Starting rolling IP rotation for {} clients, {} at a time",
+            interfaces.len(),
+            batch_size
+        );
+
+        for batch in interfaces.chunks(batch_size) {
+            if interfaces.len() > batch.len()
+                && !self.wait_for_available_outside(batch, timeout).await
+            {
+                warn!(
+                    "No other session is serving traffic, rotating {:?} anyway",
+                    batch
+                );
+            }
+
+            let started_at = Utc::now();
+            let mut previous_ips = BTreeMap::new();
+            for interface in batch {
+                previous_ips.insert(interface.clone(), self.mark_rotating(interface).await);
+            }
+
+            for interface in batch {
+                if let Err(e) = self.disconnect_client(interface).await {
+                    error!("Failed to disconnect {}: {}", interface, e);
+                }
+            }
+            time::sleep(Duration::from_secs(self.config().wait_seconds as u64)).await;
+            for interface in batch {
+                if let Err(e) = self.connect_client(interface).await {
+                    error!("Failed to connect {}: {}", interface, e);
+                }
+            }
+
+            for interface in batch {
+                let previous = previous_ips.get(interface).cloned().flatten();
+                self.reroll(interface, previous, started_at).await;
+            }
         }
 
-        time_string_to_sec(&self.config.rotation_time).unwrap_or_else(|e| {
-            error!("Failed to parse rotation time: {}", e);
-            3600
-        })
+        debug!("Rolling IP rotation completed");
+    }
+
+    /// Takes `interface` out of proxy selection and returns the IP it had.
+    async fn mark_rotating(&self, interface: &str) -> Option<String> {
+        let mut data = self.data.lock().await;
+        let info = data.entry(interface.to_string()).or_default();
+        info.rotating = true;
+        info.rotation_pending = true;
+        info.local_ip.clone()
+    }
+
+    /// Puts `interface` back into proxy selection once a rotation settled on its lease.
+    /// Without one, the next lease does that.
+    async fn finish_rotation(&self, interface: &str) {
+        let mut data = self.data.lock().await;
+        if let Some(info) = data.get_mut(interface) {
+            info.rotation_pending = false;
+            info.rotating = info.local_ip.is_none();
+        }
+    }
+
+    /// The session other than `interface` that currently holds `ip`, if any.
+    async fn ip_holder(&self, interface: &str, ip: &str) -> Option<String> {
+        let data = self.data.lock().await;
+        data.iter()
+            .find(|(other, info)| *other != interface && info.local_ip.as_deref() == Some(ip))
+            .map(|(other, _)| other.clone())
+    }
+
+    /// Waits for the lease started at `since` and reconnects again while it repeats
+    /// `previous_ip` or duplicates another session, up to `max_rerolls` times.
+    async fn reroll(
+        &self,
+        interface: &str,
+        previous_ip: Option<String>,
+        since: DateTime<Utc>,
+    ) -> RotationOutcome {
+        let timeout = Duration::from_secs(self.config().reconnect_timeout_secs);
+        let mut since = since;
+        let mut attempts = 1;
+        let new_ip = loop {
+            let Some(ip) = self.wait_for_lease(interface, since, timeout).await else {
+                error!("{}: No new lease within {}s", interface, timeout.as_secs());
+                break None;
+            };
+
+            let reason = if previous_ip.as_ref() == Some(&ip) {
+                "the previous IP".to_string()
+            } else if let Some(holder) = self.ip_holder(interface, &ip).await {
+                format!("the IP of {}", holder)
+            } else {
+                break Some(ip);
+            };
+            if attempts > self.config().max_rerolls {
+                warn!(
+                    "{}: Still got {} ({}) after {} attempts, giving up",
+                    interface, reason, ip, attempts
+                );
+                break Some(ip);
+            }
+
+            info!(
+                "{}: Got {} ({}) again, rerolling ({}/{})",
+                interface,
+                reason,
+                ip,
+                attempts,
+                self.config().max_rerolls
+            );
+            attempts += 1;
+            self.mark_rotating(interface).await;
+            since = Utc::now();
+            if let Err(e) = self.send_command(interface, ClientCommand::Reconnect).await {
+                error!("Failed to reconnect {}: {}", interface, e);
+                break Some(ip);
+            }
+        };
+
+        self.finish_rotation(interface).await;
+
+        let outcome = RotationOutcome {
+            interface: interface.to_string(),
+            previous_ip,
+            new_ip,
+            attempts,
+            finished_at: Utc::now(),
+        };
+        if let Some(sender) = self.event_sender.lock().await.as_ref() {
+            let _ = sender
+                .send(PpmsEvent::RotationCompleted(outcome.clone()))
+                .await;
+        }
+        outcome
+    }
+
+    /// Waits until `interface` has a lease obtained after `since`, returning its IP.
+    async fn wait_for_lease(
+        &self,
+        interface: &str,
+        since: DateTime<Utc>,
+        timeout: Duration,
+    ) -> Option<String> {
+        let deadline = time::Instant::now() + timeout;
+        loop {
+            {
+                let data = self.data.lock().await;
+                if let Some(info) = data.get(interface)
+                    && let (Some(ip), Some(connected_at)) = (&info.local_ip, info.connected_at)
+                    && connected_at >= since
+                {
+                    return Some(ip.clone());
+                }
+            }
+            if time::Instant::now() >= deadline {
+                return None;
+            }
+            time::sleep(Duration::from_millis(500)).await;
+        }
+    }
+
+    /// Waits until a session other than `excluded` is available to carry traffic.
+    async fn wait_for_available_outside(&self, excluded: &[String], timeout: Duration) -> bool {
+        let deadline = time::Instant::now() + timeout;
+        loop {
+            {
+                let data = self.data.lock().await;
+                if data
+                    .iter()
+                    .any(|(interface, info)| !excluded.contains(interface) && info.is_available())
+                {
+                    return true;
+                }
+            }
+            if time::Instant::now() >= deadline {
+                return false;
+            }
+            time::sleep(Duration::from_millis(500)).await;
+        }
+    }
+
+    async fn set_next_rotation(&self, interfaces: &[String], at: Option<DateTime<Utc>>) {
+        let mut data = self.data.lock().await;
+        for interface in interfaces {
+            data.entry(interface.clone()).or_default().next_rotation_at = at;
+        }
+    }
+
+    /// Rotates `interfaces` whenever `schedule` fires; returns if it never does or once
+    /// `stop` fires or is dropped, which only cuts the wait between rotations short.
+    async fn run_schedule(
+        self: Arc<Self>,
+        interfaces: Vec<String>,
+        schedule: RotationSchedule,
+        mut stop: oneshot::Receiver<()>,
+    ) {
+        loop {
+            let Some(next) = schedule.next_after(Utc::now(), self.config().timezone) else {
+                info!("IP rotation disabled for {:?}", interfaces);
+                return;
+            };
+            self.set_next_rotation(&interfaces, Some(next)).await;
+            info!(
+                "Next IP rotation for {:?} at {}",
+                interfaces,
+                format_in(next, self.config().timezone, "%Y-%m-%d %H:%M:%S %Z")
+            );
+
+            tokio::select! {
+                _ = time::sleep((next - Utc::now()).to_std().unwrap_or_default()) => {}
+                _ = &mut stop => return,
+            }
+            self.set_next_rotation(&interfaces, None).await;
+            self.rotate_ips(&interfaces).await;
+        }
     }
 
     pub async fn serve(self: Arc<Self>) {
@@ -373,17 +1061,135 @@ impl PPPoEManager {
 
         PPPoEManager::start_health_check_task(Arc::clone(&self)).await;
         self.start_all().await;
-        if self.config.rotation_time == "0" {
-            info!("IP rotation disabled");
-            loop {
-                tokio::time::sleep(Duration::from_secs(3600)).await;
+        self.start_schedules().await;
+    }
+
+    /// Starts the rotation timers the current config and sessions call for. Timers whose
+    /// sessions and schedule are unchanged keep running, so they keep their next rotation.
+    pub async fn start_schedules(self: &Arc<Self>) {
+        let mut timers = self.schedule_timers.lock().await;
+
+        let config = self.config();
+        let interfaces: Vec<String> = self.client_controls.lock().await.keys().cloned().collect();
+        for interface in config.session_schedules.keys() {
+            if !interfaces.contains(interface) {
+                warn!("Rotation schedule set for unknown session {}", interface);
             }
         }
-        loop {
-            let secs = self.calculate_next_rotation_seconds();
-            info!("Next IP rotation in {} seconds", secs);
-            time::sleep(Duration::from_secs(secs as u64)).await;
-            self.rotate_ips().await;
+
+        // Sessions with their own schedule get their own timer, the rest share the global one.
+        let (custom, shared): (Vec<String>, Vec<String>) = interfaces
+            .into_iter()
+            .partition(|interface| config.session_schedules.contains_key(interface));
+        let mut wanted: Vec<(Vec<String>, RotationSchedule)> = custom
+            .into_iter()
+            .map(|interface| {
+                let schedule = config.session_schedules[&interface].clone();
+                (vec![interface], schedule)
+            })
+            .collect();
+        if !shared.is_empty() {
+            wanted.push((shared, config.schedule.clone()));
+        }
+
+        timers.retain(|timer| {
+            wanted.iter().any(|(interfaces, schedule)| {
+                timer.interfaces == *interfaces && timer.schedule == *schedule
+            })
+        });
+        for (interfaces, schedule) in wanted {
+            if !timers
+                .iter()
+                .any(|timer| timer.interfaces == interfaces && timer.schedule == schedule)
+            {
+                timers.push(self.spawn_timer(interfaces, schedule));
+            }
+        }
+    }
+
+    fn spawn_timer(
+        self: &Arc<Self>,
+        interfaces: Vec<String>,
+        schedule: RotationSchedule,
+    ) -> ScheduleTimer {
+        let (stop, stop_rx) = oneshot::channel();
+        ScheduleTimer {
+            task: tokio::spawn(Arc::clone(self).run_schedule(
+                interfaces.clone(),
+                schedule.clone(),
+                stop_rx,
+            )),
+            interfaces,
+            schedule,
+            _stop: stop,
+        }
+    }
+
+    /// Applies new rotation and health check settings to the running sessions and
+    /// returns what had to be restarted.
+    pub async fn reload(self: &Arc<Self>, config: IpRotationConfig) -> Vec<String> {
+        let previous = std::mem::replace(&mut *self.config.write().unwrap(), Arc::new(config));
+        let current = self.config();
+        let mut restarted = Vec::new();
+
+        if previous.schedule != current.schedule
+            || previous.session_schedules != current.session_schedules
+            || previous.timezone != current.timezone
+        {
+            if previous.timezone != current.timezone {
+                // Every timer computed its next rotation in the old timezone.
+                self.schedule_timers.lock().await.clear();
+            }
+            self.start_schedules().await;
+            restarted.push("rotation schedule".to_string());
+        }
+
+        let health = |c: &IpRotationConfig| {
+            (
+                c.health_check_enabled,
+                c.health_check_interval_secs,
+                c.health_check_failure_threshold,
+                c.health_check_target.clone(),
+            )
+        };
+        if health(&previous) != health(&current) {
+            if let Some(task) = self.health_check_task.lock().await.take() {
+                task.abort();
+            }
+            PPPoEManager::start_health_check_task(Arc::clone(self)).await;
+            restarted.push("health check".to_string());
+        }
+        restarted
+    }
+
+    /// Records that `interface` stopped reconnecting until it is told to connect again.
+    async fn gave_up(&self, interface: &str, reason: ExitReason) {
+        error!(
+            "{}: Stopped reconnecting ({}); connect it again once that is fixed",
+            interface, reason
+        );
+        if let Some(info) = self.data.lock().await.get_mut(interface) {
+            info.gave_up = Some(reason.clone());
+        }
+        // Nobody listening is fine: the status still shows it.
+        let _ = self.alerts.send(Alert {
+            interface: interface.to_string(),
+            reason,
+            at: Utc::now(),
+        });
+    }
+
+    async fn record_lease(&self, interface: &str) {
+        let Some(info) = self.data.lock().await.get(interface).cloned() else {
+            return;
+        };
+        let lease = info.local_ip.as_deref().zip(info.connected_at);
+        if let Err(e) = self
+            .history
+            .observe(interface, lease, info.bytes_sent, info.bytes_received)
+            .await
+        {
+            error!("Failed to record IP history for {}: {}", interface, e);
         }
     }
 
@@ -401,12 +1207,52 @@ impl PPPoEManager {
                     interface,
                     local_ip,
                     connected_at,
+                    lease,
                 } => {
+                    {
+                        let mut data = self.data.lock().await;
+                        let info = data.entry(interface.clone()).or_default();
+                        info.lease = lease;
+                        if local_ip.is_some() {
+                            info.gave_up = None;
+                        }
+                    }
                     self.update_connection_info(&interface, local_ip, connected_at)
                         .await;
+                    self.record_lease(&interface).await;
                 }
-                PpmsEvent::Disconnected { interface } => {
+                PpmsEvent::Disconnected { interface, reason } => {
+                    if let Some(info) = self.data.lock().await.get_mut(&interface)
+                        && reason.is_some()
+                    {
+                        info.lease.termination_reason = reason;
+                    }
                     self.update_connection_info(&interface, None, None).await;
+                    self.record_lease(&interface).await;
+                }
+                PpmsEvent::GaveUp { interface, reason } => {
+                    self.gave_up(&interface, reason).await;
+                }
+                PpmsEvent::Stopped { interface } => {
+                    // Scaled back up before the old client got here: the info is the new one's.
+                    let controls = self.client_controls.lock().await;
+                    if !controls.contains_key(&interface) {
+                        self.data.lock().await.remove(&interface);
+                    }
+                }
+                PpmsEvent::RotationCompleted(outcome) => {
+                    if outcome.changed() {
+                        info!("Rotation completed: {}", outcome);
+                    } else {
+                        warn!("Rotation did not get a new IP: {}", outcome);
+                    }
+                    let mut data = self.data.lock().await;
+                    if let Some(info) = data.get_mut(&outcome.interface) {
+                        info.last_rotation = Some(outcome);
+                    }
+                }
+                PpmsEvent::RouteRepaired(repair) => {
+                    warn!("Routing drift repaired: {}", repair);
                 }
             }
         }
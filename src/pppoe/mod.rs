@@ -1,2 +1,7 @@
 pub mod client;
+pub mod exit;
+pub mod history;
+pub mod lease;
 pub mod manager;
+pub mod native;
+pub mod session_log;
@@ -0,0 +1,495 @@
+use log::{debug, warn};
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use tokio::io::unix::AsyncFd;
+use tokio::time::{Duration, timeout};
+
+use super::{NativeError, io_error};
+
+const ETH_P_PPP_DISC: u16 = 0x8863;
+
+const CODE_PADI: u8 = 0x09;
+const CODE_PADO: u8 = 0x07;
+const CODE_PADR: u8 = 0x19;
+const CODE_PADS: u8 = 0x65;
+const CODE_PADT: u8 = 0xa7;
+
+const TAG_END_OF_LIST: u16 = 0x0000;
+const TAG_SERVICE_NAME: u16 = 0x0101;
+const TAG_AC_NAME: u16 = 0x0102;
+const TAG_HOST_UNIQ: u16 = 0x0103;
+const TAG_AC_COOKIE: u16 = 0x0104;
+const TAG_RELAY_SESSION_ID: u16 = 0x0110;
+const TAG_SERVICE_NAME_ERROR: u16 = 0x0201;
+const TAG_AC_SYSTEM_ERROR: u16 = 0x0202;
+const TAG_GENERIC_ERROR: u16 = 0x0203;
+
+const BROADCAST: [u8; 6] = [0xff; 6];
+/// PADI and PADR are sent this many times, waiting twice as long after each.
+const ATTEMPTS: u32 = 3;
+const FIRST_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A PPPoE discovery packet, without the ethernet header.
+#[derive(Debug)]
+struct DiscoveryPacket {
+    code: u8,
+    session_id: u16,
+    tags: Vec<(u16, Vec<u8>)>,
+}
+
+impl DiscoveryPacket {
+    fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 6 || buf[0] != 0x11 {
+            return None;
+        }
+        let length = usize::from(u16::from_be_bytes([buf[4], buf[5]]));
+        let mut payload = buf.get(6..6 + length)?;
+        let mut tags = Vec::new();
+        while payload.len() >= 4 {
+            let kind = u16::from_be_bytes([payload[0], payload[1]]);
+            let length = usize::from(u16::from_be_bytes([payload[2], payload[3]]));
+            if kind == TAG_END_OF_LIST {
+                break;
+            }
+            tags.push((kind, payload.get(4..4 + length)?.to_vec()));
+            payload = &payload[4 + length..];
+        }
+        Some(Self {
+            code: buf[1],
+            session_id: u16::from_be_bytes([buf[2], buf[3]]),
+            tags,
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for (kind, value) in &self.tags {
+            payload.extend_from_slice(&kind.to_be_bytes());
+            payload.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            payload.extend_from_slice(value);
+        }
+        let mut buf = vec![0x11, self.code];
+        buf.extend_from_slice(&self.session_id.to_be_bytes());
+        buf.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&payload);
+        buf
+    }
+
+    fn tag(&self, kind: u16) -> Option<&[u8]> {
+        self.tags
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// The first error tag, with its text or its name if it has none.
+    fn error(&self) -> Option<String> {
+        self.tags.iter().find_map(|(kind, value)| {
+            let name = match *kind {
+                TAG_SERVICE_NAME_ERROR => "Service-Name-Error",
+                TAG_AC_SYSTEM_ERROR => "AC-System-Error",
+                TAG_GENERIC_ERROR => "Generic-Error",
+                _ => return None,
+            };
+            Some(if value.is_empty() {
+                name.to_string()
+            } else {
+                format!("{}: {}", name, String::from_utf8_lossy(value))
+            })
+        })
+    }
+}
+
+pub fn format_mac(mac: &[u8; 6]) -> String {
+    mac.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// A PPPoE session the access concentrator confirmed with a PADS.
+#[derive(Debug, Clone)]
+pub struct PppoeSession {
+    pub peer: [u8; 6],
+    pub session_id: u16,
+    pub ac_name: Option<String>,
+}
+
+/// A raw socket for discovery packets on one uplink.
+pub struct Discovery {
+    socket: AsyncFd<OwnedFd>,
+    ifindex: i32,
+    uplink: String,
+}
+
+impl Discovery {
+    pub fn open(uplink: &str) -> Result<Self, NativeError> {
+        let ifindex = CString::new(uplink)
+            .map(|name| unsafe { libc::if_nametoindex(name.as_ptr()) } as i32)
+            .unwrap_or(0);
+        if ifindex == 0 {
+            return Err(NativeError::UplinkNotFound(uplink.to_string()));
+        }
+
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_PACKET,
+                libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                i32::from(ETH_P_PPP_DISC.to_be()),
+            )
+        };
+        if fd < 0 {
+            return Err(io_error("open the discovery socket")(
+                io::Error::last_os_error(),
+            ));
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let addr = link_addr(ifindex, [0; 6]);
+        let bound = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                (&addr as *const libc::sockaddr_ll).cast(),
+                mem::size_of::<libc::sockaddr_ll>() as u32,
+            )
+        };
+        if bound < 0 {
+            return Err(io_error("bind the discovery socket")(
+                io::Error::last_os_error(),
+            ));
+        }
+
+        Ok(Self {
+            socket: AsyncFd::new(fd).map_err(io_error("register the discovery socket"))?,
+            ifindex,
+            uplink: uplink.to_string(),
+        })
+    }
+
+    /// Broadcasts PADIs until an access concentrator offers a session, then requests it.
+    pub async fn discover(&self) -> Result<PppoeSession, NativeError> {
+        let host_uniq = fastrand::u64(..).to_be_bytes().to_vec();
+        let padi = DiscoveryPacket {
+            code: CODE_PADI,
+            session_id: 0,
+            tags: vec![
+                (TAG_SERVICE_NAME, Vec::new()),
+                (TAG_HOST_UNIQ, host_uniq.clone()),
+            ],
+        };
+        let (peer, pado) = self
+            .exchange(BROADCAST, &padi, CODE_PADO, &host_uniq)
+            .await?
+            .ok_or_else(|| NativeError::NoOffer(self.uplink.clone()))?;
+        let ac_name = pado
+            .tag(TAG_AC_NAME)
+            .map(|name| String::from_utf8_lossy(name).into_owned());
+        debug!(
+            "PADO from {} ({}) on {}",
+            format_mac(&peer),
+            ac_name.as_deref().unwrap_or("unnamed"),
+            self.uplink
+        );
+
+        let mut tags = vec![
+            (TAG_SERVICE_NAME, Vec::new()),
+            (TAG_HOST_UNIQ, host_uniq.clone()),
+        ];
+        for kind in [TAG_AC_COOKIE, TAG_RELAY_SESSION_ID] {
+            if let Some(value) = pado.tag(kind) {
+                tags.push((kind, value.to_vec()));
+            }
+        }
+        let padr = DiscoveryPacket {
+            code: CODE_PADR,
+            session_id: 0,
+            tags,
+        };
+        let (_, pads) = self
+            .exchange(peer, &padr, CODE_PADS, &host_uniq)
+            .await?
+            .ok_or(NativeError::NoConfirmation)?;
+        if pads.session_id == 0 {
+            return Err(NativeError::Refused(
+                "PADS without a session ID".to_string(),
+            ));
+        }
+        Ok(PppoeSession {
+            peer,
+            session_id: pads.session_id,
+            ac_name,
+        })
+    }
+
+    /// Sends `packet` to `to` and waits for a `reply_code` answer, retrying with a growing
+    /// timeout. `None` when nothing answered.
+    async fn exchange(
+        &self,
+        to: [u8; 6],
+        packet: &DiscoveryPacket,
+        reply_code: u8,
+        host_uniq: &[u8],
+    ) -> Result<Option<([u8; 6], DiscoveryPacket)>, NativeError> {
+        let from = (to != BROADCAST).then_some(to);
+        let mut wait = FIRST_TIMEOUT;
+        for _ in 0..ATTEMPTS {
+            self.send_to(to, &packet.encode())
+                .await
+                .map_err(io_error("send a discovery packet"))?;
+            if let Ok(reply) = timeout(wait, self.wait_for(reply_code, from, host_uniq)).await {
+                return reply.map(Some);
+            }
+            wait *= 2;
+        }
+        Ok(None)
+    }
+
+    async fn wait_for(
+        &self,
+        code: u8,
+        from: Option<[u8; 6]>,
+        host_uniq: &[u8],
+    ) -> Result<([u8; 6], DiscoveryPacket), NativeError> {
+        loop {
+            let (mac, packet) = self
+                .recv()
+                .await
+                .map_err(io_error("receive a discovery packet"))?;
+            if packet.code != code
+                || from.is_some_and(|from| from != mac)
+                || packet.tag(TAG_HOST_UNIQ) != Some(host_uniq)
+            {
+                continue;
+            }
+            if let Some(error) = packet.error() {
+                // Another access concentrator may still make an offer; a PADS is final.
+                if code == CODE_PADS {
+                    return Err(NativeError::Refused(error));
+                }
+                warn!("Ignoring PADO from {} with {}", format_mac(&mac), error);
+                continue;
+            }
+            return Ok((mac, packet));
+        }
+    }
+
+    /// Resolves once the access concentrator ends `session` with a PADT.
+    pub async fn wait_padt(&self, session: &PppoeSession) -> NativeError {
+        loop {
+            match self.recv().await {
+                Ok((mac, packet))
+                    if packet.code == CODE_PADT
+                        && packet.session_id == session.session_id
+                        && mac == session.peer =>
+                {
+                    return NativeError::Padt;
+                }
+                Ok(_) => {}
+                Err(e) => return io_error("receive a discovery packet")(e),
+            }
+        }
+    }
+
+    /// Tells the access concentrator the session is over.
+    pub async fn terminate(&self, session: &PppoeSession) {
+        let padt = DiscoveryPacket {
+            code: CODE_PADT,
+            session_id: session.session_id,
+            tags: Vec::new(),
+        };
+        if let Err(e) = self.send_to(session.peer, &padt.encode()).await {
+            warn!("Failed to send PADT on {}: {}", self.uplink, e);
+        }
+    }
+
+    async fn send_to(&self, mac: [u8; 6], buf: &[u8]) -> io::Result<()> {
+        let addr = link_addr(self.ifindex, mac);
+        loop {
+            let mut guard = self.socket.writable().await?;
+            let sent = guard.try_io(|socket| {
+                let n = unsafe {
+                    libc::sendto(
+                        socket.as_raw_fd(),
+                        buf.as_ptr().cast(),
+                        buf.len(),
+                        0,
+                        (&addr as *const libc::sockaddr_ll).cast(),
+                        mem::size_of::<libc::sockaddr_ll>() as u32,
+                    )
+                };
+                if n < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(())
+                }
+            });
+            if let Ok(result) = sent {
+                return result;
+            }
+        }
+    }
+
+    /// The next incoming discovery packet and its source MAC, skipping malformed ones
+    /// and our own outgoing packets.
+    async fn recv(&self) -> io::Result<([u8; 6], DiscoveryPacket)> {
+        let mut buf = [0u8; 1500];
+        loop {
+            let mut guard = self.socket.readable().await?;
+            let received = guard.try_io(|socket| {
+                let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+                let mut addr_len = mem::size_of::<libc::sockaddr_ll>() as u32;
+                let n = unsafe {
+                    libc::recvfrom(
+                        socket.as_raw_fd(),
+                        buf.as_mut_ptr().cast(),
+                        buf.len(),
+                        0,
+                        (&mut addr as *mut libc::sockaddr_ll).cast(),
+                        &mut addr_len,
+                    )
+                };
+                if n < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok((addr, n as usize))
+                }
+            });
+            let Ok(result) = received else {
+                continue;
+            };
+            let (addr, n) = result?;
+            if addr.sll_pkttype == libc::PACKET_OUTGOING {
+                continue;
+            }
+            let mut mac = [0u8; 6];
+            mac.copy_from_slice(&addr.sll_addr[..6]);
+            if let Some(packet) = DiscoveryPacket::parse(&buf[..n]) {
+                return Ok((mac, packet));
+            }
+        }
+    }
+}
+
+fn link_addr(ifindex: i32, mac: [u8; 6]) -> libc::sockaddr_ll {
+    let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as u16;
+    addr.sll_protocol = ETH_P_PPP_DISC.to_be();
+    addr.sll_ifindex = ifindex;
+    addr.sll_halen = 6;
+    addr.sll_addr[..6].copy_from_slice(&mac);
+    addr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST_UNIQ: [u8; 8] = [0xde, 0xad, 0xbe, 0xef, 0, 0, 0, 1];
+
+    /// A PADO like rp-pppoe's `pppoe-server` sends, padded to the ethernet minimum.
+    fn pado() -> Vec<u8> {
+        let mut buf = vec![0x11, CODE_PADO, 0x00, 0x00, 0x00, 0x24];
+        buf.extend_from_slice(&[0x01, 0x01, 0x00, 0x00]);
+        buf.extend_from_slice(&[0x01, 0x02, 0x00, 0x04]);
+        buf.extend_from_slice(b"isp1");
+        buf.extend_from_slice(&[0x01, 0x03, 0x00, 0x08]);
+        buf.extend_from_slice(&HOST_UNIQ);
+        buf.extend_from_slice(&[0x01, 0x04, 0x00, 0x04, 0xaa, 0xbb, 0xcc, 0xdd]);
+        buf.resize(46, 0);
+        buf
+    }
+
+    #[test]
+    fn decodes_pado() {
+        let packet = DiscoveryPacket::parse(&pado()).unwrap();
+        assert_eq!((packet.code, packet.session_id), (CODE_PADO, 0));
+        assert_eq!(packet.tag(TAG_SERVICE_NAME), Some(&[][..]));
+        assert_eq!(packet.tag(TAG_AC_NAME), Some(&b"isp1"[..]));
+        assert_eq!(packet.tag(TAG_HOST_UNIQ), Some(&HOST_UNIQ[..]));
+        assert_eq!(
+            packet.tag(TAG_AC_COOKIE),
+            Some(&[0xaa, 0xbb, 0xcc, 0xdd][..])
+        );
+        assert_eq!(packet.error(), None);
+    }
+
+    #[test]
+    fn decodes_pads_session_id() {
+        let mut buf = vec![0x11, CODE_PADS, 0x12, 0x34, 0x00, 0x0c];
+        buf.extend_from_slice(&[0x01, 0x03, 0x00, 0x08]);
+        buf.extend_from_slice(&HOST_UNIQ);
+        let packet = DiscoveryPacket::parse(&buf).unwrap();
+        assert_eq!((packet.code, packet.session_id), (CODE_PADS, 0x1234));
+    }
+
+    #[test]
+    fn encoded_padr_decodes_to_the_same() {
+        let padr = DiscoveryPacket {
+            code: CODE_PADR,
+            session_id: 0,
+            tags: vec![
+                (TAG_SERVICE_NAME, Vec::new()),
+                (TAG_HOST_UNIQ, HOST_UNIQ.to_vec()),
+                (TAG_AC_COOKIE, vec![1, 2, 3]),
+            ],
+        };
+        let buf = padr.encode();
+        assert_eq!(&buf[..6], &[0x11, CODE_PADR, 0, 0, 0, 23]);
+        let decoded = DiscoveryPacket::parse(&buf).unwrap();
+        assert_eq!(decoded.code, CODE_PADR);
+        assert_eq!(decoded.tags, padr.tags);
+    }
+
+    #[test]
+    fn stops_at_end_of_list() {
+        let mut buf = vec![0x11, CODE_PADO, 0, 0, 0x00, 0x0c];
+        buf.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+        buf.extend_from_slice(&[0x01, 0x02, 0x00, 0x04]);
+        buf.extend_from_slice(b"isp1");
+        let packet = DiscoveryPacket::parse(&buf).unwrap();
+        assert!(packet.tags.is_empty());
+    }
+
+    #[test]
+    fn reports_error_tags() {
+        let mut buf = vec![0x11, CODE_PADS, 0, 0, 0x00, 0x0a];
+        buf.extend_from_slice(&[0x02, 0x01, 0x00, 0x06]);
+        buf.extend_from_slice(b"no svc");
+        let packet = DiscoveryPacket::parse(&buf).unwrap();
+        assert_eq!(
+            packet.error().as_deref(),
+            Some("Service-Name-Error: no svc")
+        );
+
+        let buf = [0x11, CODE_PADS, 0, 0, 0x00, 0x04, 0x02, 0x03, 0x00, 0x00];
+        let packet = DiscoveryPacket::parse(&buf).unwrap();
+        assert_eq!(packet.error().as_deref(), Some("Generic-Error"));
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        // Wrong version and type.
+        let mut buf = pado();
+        buf[0] = 0x21;
+        assert!(DiscoveryPacket::parse(&buf).is_none());
+        // Shorter than the header.
+        assert!(DiscoveryPacket::parse(&[0x11, CODE_PADO, 0, 0]).is_none());
+        // Payload length past the end of the frame.
+        assert!(DiscoveryPacket::parse(&[0x11, CODE_PADO, 0, 0, 0x00, 0x40, 0, 0]).is_none());
+        // A tag running past the payload.
+        let buf = [
+            0x11, CODE_PADO, 0, 0, 0x00, 0x06, 0x01, 0x02, 0x00, 0x08, b'i', b's',
+        ];
+        assert!(DiscoveryPacket::parse(&buf).is_none());
+    }
+
+    #[test]
+    fn formats_mac() {
+        assert_eq!(
+            format_mac(&[0x02, 0x00, 0x5e, 0x10, 0xab, 0xff]),
+            "02:00:5e:10:ab:ff"
+        );
+    }
+}
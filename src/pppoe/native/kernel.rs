@@ -0,0 +1,277 @@
+use futures::TryStreamExt;
+use netlink_packet_route::nlas::address;
+use std::ffi::CString;
+use std::fs::OpenOptions;
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use tokio::io::unix::AsyncFd;
+
+use super::discovery::PppoeSession;
+use super::{NativeError, io_error};
+
+const PX_PROTO_OE: i32 = 0;
+const PPP_IP: i32 = 0x0021;
+const NPMODE_PASS: i32 = 0;
+
+// From linux/ppp-ioctl.h, as encoded on x86 and arm.
+const PPPIOCGCHAN: u32 = 0x8004_7437;
+const PPPIOCATTCHAN: u32 = 0x4004_7438;
+const PPPIOCCONNECT: u32 = 0x4004_743a;
+const PPPIOCNEWUNIT: u32 = 0xc004_743e;
+const PPPIOCSMRU: u32 = 0x4004_7452;
+const PPPIOCSNPMODE: u32 = 0x4008_744b;
+
+/// `struct sockaddr_pppox` with its `pppoe_addr`, packed like the kernel's.
+#[repr(C, packed)]
+struct SockaddrPppox {
+    sa_family: u16,
+    sa_protocol: u32,
+    sid: u16,
+    remote: [u8; 6],
+    dev: [u8; 16],
+}
+
+#[repr(C)]
+struct NpIoctl {
+    protocol: i32,
+    mode: i32,
+}
+
+fn ioctl<T>(
+    fd: &impl AsRawFd,
+    request: u32,
+    arg: &mut T,
+    what: &'static str,
+) -> Result<(), NativeError> {
+    let result = unsafe {
+        libc::ioctl(
+            fd.as_raw_fd(),
+            request as libc::Ioctl,
+            (arg as *mut T).cast::<libc::c_void>(),
+        )
+    };
+    if result < 0 {
+        return Err(io_error(what)(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+fn open_ppp() -> Result<OwnedFd, NativeError> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open("/dev/ppp")
+        .map(OwnedFd::from)
+        .map_err(io_error("open /dev/ppp"))
+}
+
+/// The `pppN` network interface of a session.
+struct Unit {
+    fd: AsyncFd<OwnedFd>,
+}
+
+/// The kernel side of a PPPoE session: the PPPoX socket, the `/dev/ppp` channel that
+/// carries LCP and authentication, and later the unit behind the network interface,
+/// which carries IPCP. Dropping it deletes the interface.
+pub struct Channel {
+    _socket: OwnedFd,
+    fd: AsyncFd<OwnedFd>,
+    unit: Option<Unit>,
+}
+
+impl Channel {
+    pub fn open(uplink: &str, session: &PppoeSession) -> Result<Self, NativeError> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_PPPOX,
+                libc::SOCK_STREAM | libc::SOCK_CLOEXEC,
+                PX_PROTO_OE,
+            )
+        };
+        if fd < 0 {
+            return Err(io_error("open a PPPoE socket")(io::Error::last_os_error()));
+        }
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut dev = [0u8; 16];
+        let name =
+            CString::new(uplink).map_err(|_| NativeError::UplinkNotFound(uplink.to_string()))?;
+        let name = name.as_bytes();
+        dev[..name.len().min(15)].copy_from_slice(&name[..name.len().min(15)]);
+        let addr = SockaddrPppox {
+            sa_family: libc::AF_PPPOX as u16,
+            sa_protocol: PX_PROTO_OE as u32,
+            sid: session.session_id.to_be(),
+            remote: session.peer,
+            dev,
+        };
+        let connected = unsafe {
+            libc::connect(
+                socket.as_raw_fd(),
+                (&addr as *const SockaddrPppox).cast(),
+                mem::size_of::<SockaddrPppox>() as u32,
+            )
+        };
+        if connected < 0 {
+            return Err(io_error("connect the PPPoE socket")(
+                io::Error::last_os_error(),
+            ));
+        }
+
+        let mut index: i32 = 0;
+        ioctl(&socket, PPPIOCGCHAN, &mut index, "get the PPP channel")?;
+        let fd = open_ppp()?;
+        ioctl(&fd, PPPIOCATTCHAN, &mut index, "attach to the PPP channel")?;
+
+        Ok(Self {
+            _socket: socket,
+            fd: AsyncFd::new(fd).map_err(io_error("register the PPP channel"))?,
+            unit: None,
+        })
+    }
+
+    /// Creates the network interface and connects the channel to it. `pppN` interfaces
+    /// get unit N so they need no renaming. Returns the kernel's name for it.
+    pub fn create_unit(&mut self, interface: &str) -> Result<String, NativeError> {
+        let fd = open_ppp()?;
+        let mut number = interface
+            .strip_prefix("ppp")
+            .and_then(|n| n.parse::<i32>().ok())
+            .unwrap_or(-1);
+        ioctl(&fd, PPPIOCNEWUNIT, &mut number, "create the PPP unit")?;
+        ioctl(
+            &self.fd,
+            PPPIOCCONNECT,
+            &mut number.clone(),
+            "connect the PPP unit",
+        )?;
+        self.unit = Some(Unit {
+            fd: AsyncFd::new(fd).map_err(io_error("register the PPP unit"))?,
+        });
+        Ok(format!("ppp{}", number))
+    }
+
+    /// Lets IP through the unit, which takes frames up to `mru` bytes.
+    pub fn enable_ip(&self, mru: u16) -> Result<(), NativeError> {
+        let Some(unit) = &self.unit else {
+            return Ok(());
+        };
+        ioctl(&unit.fd, PPPIOCSMRU, &mut i32::from(mru), "set the MRU")?;
+        let mut npmode = NpIoctl {
+            protocol: PPP_IP,
+            mode: NPMODE_PASS,
+        };
+        ioctl(
+            &unit.fd,
+            PPPIOCSNPMODE,
+            &mut npmode,
+            "enable IP on the unit",
+        )
+    }
+
+    /// The next control frame from the channel or the unit.
+    pub async fn recv(&self) -> io::Result<Vec<u8>> {
+        match &self.unit {
+            Some(unit) => tokio::select! {
+                frame = read_frame(&self.fd) => frame,
+                frame = read_frame(&unit.fd) => frame,
+            },
+            None => read_frame(&self.fd).await,
+        }
+    }
+
+    pub async fn send(&self, frame: &[u8]) -> io::Result<()> {
+        loop {
+            let mut guard = self.fd.writable().await?;
+            let written = guard.try_io(|fd| {
+                let n = unsafe { libc::write(fd.as_raw_fd(), frame.as_ptr().cast(), frame.len()) };
+                if n < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(())
+                }
+            });
+            if let Ok(result) = written {
+                return result;
+            }
+        }
+    }
+}
+
+async fn read_frame(fd: &AsyncFd<OwnedFd>) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; 1500];
+    loop {
+        let mut guard = fd.readable().await?;
+        let read = guard.try_io(|fd| {
+            let n = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+            if n < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(n as usize)
+            }
+        });
+        if let Ok(result) = read {
+            buf.truncate(result?);
+            return Ok(buf);
+        }
+    }
+}
+
+/// Names the unit's interface `interface`, sets its MTU and point-to-point addresses
+/// and brings it up.
+pub async fn configure_interface(
+    unit_name: &str,
+    interface: &str,
+    mtu: u16,
+    local: Ipv4Addr,
+    peer: Ipv4Addr,
+) -> Result<(), NativeError> {
+    let netlink_error = |what: &str| {
+        let what = format!("{} {}", what, interface);
+        move |source| NativeError::Netlink { what, source }
+    };
+    let (connection, handle, _) =
+        rtnetlink::new_connection().map_err(io_error("open a netlink socket"))?;
+    let connection = tokio::spawn(connection);
+
+    let result = async {
+        let link = handle
+            .link()
+            .get()
+            .match_name(unit_name.to_string())
+            .execute()
+            .try_next()
+            .await
+            .map_err(netlink_error("look up"))?
+            .ok_or_else(|| io_error("find the new interface")(io::ErrorKind::NotFound.into()))?;
+        let index = link.header.index;
+
+        let mut set = handle.link().set(index).mtu(u32::from(mtu));
+        if unit_name != interface {
+            set = set.name(interface.to_string());
+        }
+        set.execute().await.map_err(netlink_error("rename"))?;
+
+        let mut add = handle.address().add(index, local.into(), 32);
+        add.message_mut().nlas = vec![
+            address::Nla::Local(local.octets().to_vec()),
+            address::Nla::Address(peer.octets().to_vec()),
+        ];
+        add.execute().await.map_err(netlink_error("address"))?;
+
+        handle
+            .link()
+            .set(index)
+            .up()
+            .execute()
+            .await
+            .map_err(netlink_error("bring up"))
+    }
+    .await;
+    connection.abort();
+    result
+}
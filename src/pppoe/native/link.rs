@@ -0,0 +1,629 @@
+use chrono::Utc;
+use log::{debug, info};
+use md5::{Digest, Md5};
+use std::net::Ipv4Addr;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Duration, Instant, sleep_until, timeout};
+
+use super::discovery::{Discovery, PppoeSession};
+use super::kernel::{self, Channel};
+use super::packet::*;
+use super::{NativeError, io_error};
+use crate::pppoe::lease::Lease;
+use crate::pppoe::manager::PpmsEvent;
+
+/// PPPoE leaves 1492 of the ethernet MTU for PPP.
+const MAX_MRU: u16 = 1492;
+/// How long to wait for an answer before sending again, and how often, like pppd.
+const RESTART_INTERVAL: Duration = Duration::from_secs(3);
+const MAX_ATTEMPTS: u32 = 10;
+const ECHO_INTERVAL: Duration = Duration::from_secs(30);
+const ECHO_FAILURES: u32 = 4;
+
+const LCP_MRU: u8 = 1;
+const LCP_ACCM: u8 = 2;
+const LCP_AUTH: u8 = 3;
+const LCP_MAGIC: u8 = 5;
+
+const IPCP_ADDRESS: u8 = 3;
+const IPCP_PRIMARY_DNS: u8 = 129;
+const IPCP_SECONDARY_DNS: u8 = 131;
+
+const AUTH_PAP: [u8; 2] = [0xc0, 0x23];
+const AUTH_CHAP_MD5: [u8; 3] = [0xc2, 0x23, 5];
+
+/// Remote address used when the peer never names its own, as pppd does.
+const DEFAULT_PEER: Ipv4Addr = Ipv4Addr::new(10, 64, 64, 64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Establish,
+    Authenticate,
+    Network,
+    Opened,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Auth {
+    Pap,
+    ChapMd5,
+}
+
+/// One side's options of LCP or IPCP and whether both sides acked.
+struct Negotiation {
+    request: Vec<ConfigOption>,
+    id: u8,
+    acked: bool,
+    peer_acked: bool,
+}
+
+impl Negotiation {
+    fn new(request: Vec<ConfigOption>) -> Self {
+        Self {
+            request,
+            id: 0,
+            acked: false,
+            peer_acked: false,
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.acked && self.peer_acked
+    }
+
+    fn option(&self, kind: u8) -> Option<&[u8]> {
+        self.request
+            .iter()
+            .find(|option| option.kind == kind)
+            .map(|option| option.data.as_slice())
+    }
+}
+
+fn ipv4(data: &[u8]) -> Option<Ipv4Addr> {
+    <[u8; 4]>::try_from(data).ok().map(Ipv4Addr::from)
+}
+
+/// LCP, authentication and IPCP over a PPPoE channel, then keepalives once the
+/// interface is up.
+pub struct Link {
+    channel: Channel,
+    interface: String,
+    username: String,
+    password: String,
+    event_sender: mpsc::Sender<PpmsEvent>,
+    phase: Phase,
+    lcp: Negotiation,
+    ipcp: Negotiation,
+    magic: u32,
+    /// The largest frame the peer takes, which caps our MTU.
+    peer_mru: u16,
+    auth: Option<Auth>,
+    peer_ip: Option<Ipv4Addr>,
+    /// The kernel's name for the interface until it is renamed.
+    unit_name: Option<String>,
+    /// Filled in as negotiation goes, reported once the interface is up.
+    lease: Lease,
+    next_id: u8,
+    deadline: Instant,
+    attempts: u32,
+    pap_id: u8,
+}
+
+impl Link {
+    pub fn new(
+        channel: Channel,
+        session: &PppoeSession,
+        interface: String,
+        username: String,
+        password: String,
+        event_sender: mpsc::Sender<PpmsEvent>,
+    ) -> Self {
+        let magic = fastrand::u32(1..);
+        Self {
+            channel,
+            interface,
+            username,
+            password,
+            event_sender,
+            phase: Phase::Establish,
+            lcp: Negotiation::new(vec![
+                ConfigOption::new(LCP_MRU, MAX_MRU.to_be_bytes()),
+                ConfigOption::new(LCP_MAGIC, magic.to_be_bytes()),
+            ]),
+            ipcp: Negotiation::new(vec![
+                ConfigOption::new(IPCP_ADDRESS, [0; 4]),
+                ConfigOption::new(IPCP_PRIMARY_DNS, [0; 4]),
+                ConfigOption::new(IPCP_SECONDARY_DNS, [0; 4]),
+            ]),
+            magic,
+            peer_mru: MAX_MRU,
+            auth: None,
+            peer_ip: None,
+            unit_name: None,
+            lease: Lease {
+                session_id: Some(session.session_id),
+                ac_name: session.ac_name.clone(),
+                ..Lease::default()
+            },
+            next_id: fastrand::u8(..),
+            deadline: Instant::now() + RESTART_INTERVAL,
+            attempts: 0,
+            pap_id: 0,
+        }
+    }
+
+    /// Brings the link up and keeps it up. Returns `Ok` once `stop` fires and the peer
+    /// was told, or the reason the link went down.
+    pub async fn run(
+        &mut self,
+        discovery: &Discovery,
+        session: &PppoeSession,
+        stop: &mut oneshot::Receiver<()>,
+    ) -> Result<(), NativeError> {
+        self.send_request(PPP_LCP).await?;
+        loop {
+            tokio::select! {
+                frame = self.channel.recv() => {
+                    let frame = frame.map_err(io_error("read from /dev/ppp"))?;
+                    self.handle_frame(&frame).await?;
+                }
+                _ = sleep_until(self.deadline) => self.handle_timeout().await?,
+                error = discovery.wait_padt(session) => return Err(error),
+                _ = &mut *stop => {
+                    self.terminate("User request").await;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Sends an LCP Terminate-Request and waits a little for the ack.
+    async fn terminate(&mut self, reason: &str) {
+        let id = self.next_id();
+        let packet = ControlPacket::new(TERM_REQ, id, reason.as_bytes().to_vec());
+        if self.channel.send(&packet.frame(PPP_LCP)).await.is_err() {
+            return;
+        }
+        let _ = timeout(RESTART_INTERVAL, async {
+            while let Ok(frame) = self.channel.recv().await {
+                if frame.starts_with(&PPP_LCP.to_be_bytes())
+                    && ControlPacket::parse(&frame[2..])
+                        .is_some_and(|packet| packet.code == TERM_ACK && packet.id == id)
+                {
+                    break;
+                }
+            }
+        })
+        .await;
+    }
+
+    fn next_id(&mut self) -> u8 {
+        self.next_id = self.next_id.wrapping_add(1);
+        self.next_id
+    }
+
+    async fn send(&self, protocol: u16, packet: ControlPacket) -> Result<(), NativeError> {
+        self.channel
+            .send(&packet.frame(protocol))
+            .await
+            .map_err(io_error("write to /dev/ppp"))
+    }
+
+    /// Sends our Configure-Request for LCP or IPCP and restarts the retransmit timer.
+    async fn send_request(&mut self, protocol: u16) -> Result<(), NativeError> {
+        let id = self.next_id();
+        let negotiation = match protocol {
+            PPP_LCP => &mut self.lcp,
+            _ => &mut self.ipcp,
+        };
+        negotiation.id = id;
+        let data = ConfigOption::encode_all(&negotiation.request);
+        self.deadline = Instant::now() + RESTART_INTERVAL;
+        self.send(protocol, ControlPacket::new(CONF_REQ, id, data))
+            .await
+    }
+
+    async fn handle_timeout(&mut self) -> Result<(), NativeError> {
+        self.attempts += 1;
+        match self.phase {
+            Phase::Establish => {
+                if self.attempts >= MAX_ATTEMPTS {
+                    return Err(NativeError::LcpTimeout);
+                }
+                if self.lcp.acked {
+                    self.deadline = Instant::now() + RESTART_INTERVAL;
+                    Ok(())
+                } else {
+                    self.send_request(PPP_LCP).await
+                }
+            }
+            Phase::Authenticate => {
+                if self.attempts >= MAX_ATTEMPTS {
+                    return Err(NativeError::AuthTimeout);
+                }
+                // A CHAP peer sends the challenge; there is nothing to resend.
+                match self.auth {
+                    Some(Auth::Pap) => self.send_pap().await,
+                    _ => {
+                        self.deadline = Instant::now() + RESTART_INTERVAL;
+                        Ok(())
+                    }
+                }
+            }
+            Phase::Network => {
+                if self.attempts >= MAX_ATTEMPTS {
+                    return Err(NativeError::IpcpTimeout);
+                }
+                if self.ipcp.acked {
+                    self.deadline = Instant::now() + RESTART_INTERVAL;
+                    Ok(())
+                } else {
+                    self.send_request(PPP_IPCP).await
+                }
+            }
+            Phase::Opened => {
+                // Every unanswered echo counts; a reply resets `attempts`.
+                if self.attempts > ECHO_FAILURES {
+                    return Err(NativeError::PeerNotResponding);
+                }
+                let id = self.next_id();
+                self.deadline = Instant::now() + ECHO_INTERVAL;
+                let data = self.magic.to_be_bytes().to_vec();
+                self.send(PPP_LCP, ControlPacket::new(ECHO_REQ, id, data))
+                    .await
+            }
+        }
+    }
+
+    async fn handle_frame(&mut self, frame: &[u8]) -> Result<(), NativeError> {
+        if frame.len() < 2 {
+            return Ok(());
+        }
+        let protocol = u16::from_be_bytes([frame[0], frame[1]]);
+        let packet = match protocol {
+            PPP_LCP | PPP_PAP | PPP_CHAP | PPP_IPCP => ControlPacket::parse(&frame[2..]),
+            _ => None,
+        };
+        match (protocol, packet) {
+            (PPP_LCP, Some(packet)) => self.handle_lcp(packet).await?,
+            (PPP_PAP, Some(packet)) => self.handle_pap(packet).await?,
+            (PPP_CHAP, Some(packet)) => self.handle_chap(packet).await?,
+            // IPCP before authentication finished is silently dropped.
+            (PPP_IPCP, Some(packet)) if matches!(self.phase, Phase::Network | Phase::Opened) => {
+                self.handle_ipcp(packet).await?
+            }
+            (PPP_LCP | PPP_PAP | PPP_CHAP | PPP_IPCP, _) => {}
+            _ if self.phase != Phase::Establish => {
+                debug!("{}: rejecting protocol {:#06x}", self.interface, protocol);
+                let id = self.next_id();
+                let length = frame.len().min(usize::from(self.peer_mru) - 4);
+                let packet = ControlPacket::new(PROTO_REJ, id, frame[..length].to_vec());
+                self.send(PPP_LCP, packet).await?;
+            }
+            _ => {}
+        }
+        self.advance().await
+    }
+
+    /// Moves on to the next phase once the current one is done.
+    async fn advance(&mut self) -> Result<(), NativeError> {
+        if self.phase == Phase::Establish && self.lcp.is_open() {
+            self.attempts = 0;
+            match self.auth {
+                Some(auth) => {
+                    debug!("{}: LCP up, authenticating with {:?}", self.interface, auth);
+                    self.phase = Phase::Authenticate;
+                    self.deadline = Instant::now() + RESTART_INTERVAL;
+                    if auth == Auth::Pap {
+                        self.send_pap().await?;
+                    }
+                }
+                None => self.start_network().await?,
+            }
+        }
+        if self.phase == Phase::Network && self.ipcp.is_open() {
+            self.open().await?;
+        }
+        Ok(())
+    }
+
+    async fn start_network(&mut self) -> Result<(), NativeError> {
+        self.unit_name = Some(self.channel.create_unit(&self.interface)?);
+        self.phase = Phase::Network;
+        self.attempts = 0;
+        self.send_request(PPP_IPCP).await
+    }
+
+    /// Brings the interface up and reports its address.
+    async fn open(&mut self) -> Result<(), NativeError> {
+        let local_ip = self
+            .ipcp
+            .option(IPCP_ADDRESS)
+            .and_then(ipv4)
+            .filter(|ip| !ip.is_unspecified())
+            .ok_or(NativeError::NoAddress)?;
+        let peer_ip = self.peer_ip.unwrap_or(DEFAULT_PEER);
+        let mtu = self.peer_mru.min(MAX_MRU);
+        let mru = u16::from_be_bytes(
+            self.lcp
+                .option(LCP_MRU)
+                .and_then(|data| data.try_into().ok())
+                .unwrap_or(MAX_MRU.to_be_bytes()),
+        );
+        let dns = |kind| {
+            self.ipcp
+                .option(kind)
+                .and_then(ipv4)
+                .filter(|ip| !ip.is_unspecified())
+        };
+        self.lease.remote_ip = Some(peer_ip);
+        self.lease.primary_dns = dns(IPCP_PRIMARY_DNS);
+        self.lease.secondary_dns = dns(IPCP_SECONDARY_DNS);
+        self.lease.mtu = Some(mtu);
+        self.lease.mru = Some(mru);
+        self.lease.auth_method = self.auth.map(|auth| match auth {
+            Auth::Pap => "PAP".to_string(),
+            Auth::ChapMd5 => "CHAP".to_string(),
+        });
+
+        self.channel.enable_ip(mru)?;
+        let unit_name = self.unit_name.clone().unwrap_or_default();
+        kernel::configure_interface(&unit_name, &self.interface, mtu, local_ip, peer_ip).await?;
+
+        let dns: Vec<String> = [self.lease.primary_dns, self.lease.secondary_dns]
+            .iter()
+            .flatten()
+            .map(|ip| ip.to_string())
+            .collect();
+        info!(
+            "{}: local IP {}, remote IP {}, MTU {}, DNS {}",
+            self.interface,
+            local_ip,
+            peer_ip,
+            mtu,
+            if dns.is_empty() {
+                "none".to_string()
+            } else {
+                dns.join(", ")
+            }
+        );
+        self.phase = Phase::Opened;
+        self.attempts = 0;
+        self.deadline = Instant::now() + ECHO_INTERVAL;
+        let _ = self
+            .event_sender
+            .send(PpmsEvent::IpUpdated {
+                interface: self.interface.clone(),
+                local_ip: Some(local_ip.to_string()),
+                connected_at: Some(Utc::now()),
+                lease: self.lease.clone(),
+            })
+            .await;
+        Ok(())
+    }
+
+    async fn handle_lcp(&mut self, packet: ControlPacket) -> Result<(), NativeError> {
+        match packet.code {
+            CONF_REQ => {
+                if self.phase != Phase::Establish {
+                    return Err(NativeError::Renegotiated);
+                }
+                let Some(options) = ConfigOption::parse_all(&packet.data) else {
+                    return Ok(());
+                };
+                let (mut nak, mut rej) = (Vec::new(), Vec::new());
+                let (mut peer_mru, mut auth) = (MAX_MRU, None);
+                for option in options {
+                    match (option.kind, option.data.as_slice()) {
+                        (LCP_MRU, [hi, lo]) => peer_mru = u16::from_be_bytes([*hi, *lo]),
+                        (LCP_ACCM, [_, _, _, _]) | (LCP_MAGIC, [_, _, _, _]) => {}
+                        (LCP_AUTH, data) if data == AUTH_PAP => auth = Some(Auth::Pap),
+                        (LCP_AUTH, data) if data == AUTH_CHAP_MD5 => auth = Some(Auth::ChapMd5),
+                        // MS-CHAP, EAP and the like: ask for CHAP-MD5 instead.
+                        (LCP_AUTH, _) => nak.push(ConfigOption::new(LCP_AUTH, AUTH_CHAP_MD5)),
+                        _ => rej.push(option),
+                    }
+                }
+                let (code, data) = if !rej.is_empty() {
+                    (CONF_REJ, ConfigOption::encode_all(&rej))
+                } else if !nak.is_empty() {
+                    (CONF_NAK, ConfigOption::encode_all(&nak))
+                } else {
+                    self.lcp.peer_acked = true;
+                    self.peer_mru = peer_mru;
+                    self.auth = auth;
+                    (CONF_ACK, packet.data)
+                };
+                self.send(PPP_LCP, ControlPacket::new(code, packet.id, data))
+                    .await
+            }
+            CONF_ACK if packet.id == self.lcp.id => {
+                self.lcp.acked = true;
+                Ok(())
+            }
+            CONF_NAK if packet.id == self.lcp.id => {
+                for option in ConfigOption::parse_all(&packet.data).unwrap_or_default() {
+                    match (option.kind, option.data.as_slice()) {
+                        (LCP_MRU, [hi, lo]) if u16::from_be_bytes([*hi, *lo]) <= MAX_MRU => {
+                            self.set_lcp_option(option);
+                        }
+                        (LCP_MAGIC, _) => {
+                            self.magic = fastrand::u32(1..);
+                            self.set_lcp_option(ConfigOption::new(
+                                LCP_MAGIC,
+                                self.magic.to_be_bytes(),
+                            ));
+                        }
+                        _ => {}
+                    }
+                }
+                self.send_request(PPP_LCP).await
+            }
+            CONF_REJ if packet.id == self.lcp.id => {
+                let rejected = ConfigOption::parse_all(&packet.data).unwrap_or_default();
+                self.lcp
+                    .request
+                    .retain(|option| !rejected.iter().any(|r| r.kind == option.kind));
+                if rejected.iter().any(|r| r.kind == LCP_MAGIC) {
+                    self.magic = 0;
+                }
+                self.send_request(PPP_LCP).await
+            }
+            TERM_REQ => {
+                self.send(PPP_LCP, ControlPacket::new(TERM_ACK, packet.id, Vec::new()))
+                    .await?;
+                Err(NativeError::Terminated(reason(&packet.data)))
+            }
+            ECHO_REQ if self.phase != Phase::Establish => {
+                let mut data = self.magic.to_be_bytes().to_vec();
+                data.extend_from_slice(packet.data.get(4..).unwrap_or_default());
+                self.send(PPP_LCP, ControlPacket::new(ECHO_REPLY, packet.id, data))
+                    .await
+            }
+            ECHO_REPLY if self.phase == Phase::Opened => {
+                self.attempts = 0;
+                Ok(())
+            }
+            PROTO_REJ if packet.data.starts_with(&PPP_IPCP.to_be_bytes()) => {
+                Err(NativeError::NoAddress)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn set_lcp_option(&mut self, option: ConfigOption) {
+        match self.lcp.request.iter_mut().find(|o| o.kind == option.kind) {
+            Some(current) => *current = option,
+            None => self.lcp.request.push(option),
+        }
+    }
+
+    async fn send_pap(&mut self) -> Result<(), NativeError> {
+        let mut data = vec![self.username.len() as u8];
+        data.extend_from_slice(self.username.as_bytes());
+        data.push(self.password.len() as u8);
+        data.extend_from_slice(self.password.as_bytes());
+        self.pap_id = self.next_id();
+        self.deadline = Instant::now() + RESTART_INTERVAL;
+        self.send(PPP_PAP, ControlPacket::new(1, self.pap_id, data))
+            .await
+    }
+
+    async fn handle_pap(&mut self, packet: ControlPacket) -> Result<(), NativeError> {
+        if self.phase != Phase::Authenticate || packet.id != self.pap_id {
+            return Ok(());
+        }
+        match packet.code {
+            // Authenticate-Ack
+            2 => {
+                info!("{}: PAP authentication succeeded", self.interface);
+                self.start_network().await
+            }
+            // Authenticate-Nak
+            3 => Err(NativeError::AuthFailed(reason(
+                packet.data.get(1..).unwrap_or_default(),
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    async fn handle_chap(&mut self, packet: ControlPacket) -> Result<(), NativeError> {
+        match packet.code {
+            // Challenge, also sent again while the link is up.
+            1 => {
+                let size = usize::from(*packet.data.first().unwrap_or(&0));
+                let Some(challenge) = packet.data.get(1..1 + size) else {
+                    return Ok(());
+                };
+                let mut hash = Md5::new();
+                hash.update([packet.id]);
+                hash.update(self.password.as_bytes());
+                hash.update(challenge);
+                let mut data = vec![16];
+                data.extend_from_slice(&hash.finalize());
+                data.extend_from_slice(self.username.as_bytes());
+                self.send(PPP_CHAP, ControlPacket::new(2, packet.id, data))
+                    .await
+            }
+            // Success
+            3 if self.phase == Phase::Authenticate => {
+                info!("{}: CHAP authentication succeeded", self.interface);
+                self.start_network().await
+            }
+            // Failure
+            4 => Err(NativeError::AuthFailed(reason(&packet.data))),
+            _ => Ok(()),
+        }
+    }
+
+    async fn handle_ipcp(&mut self, packet: ControlPacket) -> Result<(), NativeError> {
+        match packet.code {
+            CONF_REQ => {
+                let Some(options) = ConfigOption::parse_all(&packet.data) else {
+                    return Ok(());
+                };
+                let mut peer_ip = None;
+                let mut rej = Vec::new();
+                for option in options {
+                    match (option.kind, ipv4(&option.data)) {
+                        (IPCP_ADDRESS, Some(ip)) => peer_ip = Some(ip),
+                        _ => rej.push(option),
+                    }
+                }
+                let (code, data) = if rej.is_empty() {
+                    self.ipcp.peer_acked = true;
+                    self.peer_ip = peer_ip.filter(|ip| !ip.is_unspecified());
+                    (CONF_ACK, packet.data)
+                } else {
+                    (CONF_REJ, ConfigOption::encode_all(&rej))
+                };
+                self.send(PPP_IPCP, ControlPacket::new(code, packet.id, data))
+                    .await
+            }
+            CONF_ACK if packet.id == self.ipcp.id => {
+                self.ipcp.acked = true;
+                Ok(())
+            }
+            // The peer fills in our address and DNS servers.
+            CONF_NAK if packet.id == self.ipcp.id => {
+                for option in ConfigOption::parse_all(&packet.data).unwrap_or_default() {
+                    if let Some(current) =
+                        self.ipcp.request.iter_mut().find(|o| o.kind == option.kind)
+                    {
+                        *current = option;
+                    }
+                }
+                self.send_request(PPP_IPCP).await
+            }
+            CONF_REJ if packet.id == self.ipcp.id => {
+                let rejected = ConfigOption::parse_all(&packet.data).unwrap_or_default();
+                if rejected.iter().any(|r| r.kind == IPCP_ADDRESS) {
+                    return Err(NativeError::NoAddress);
+                }
+                self.ipcp
+                    .request
+                    .retain(|option| !rejected.iter().any(|r| r.kind == option.kind));
+                self.send_request(PPP_IPCP).await
+            }
+            TERM_REQ => {
+                self.send(
+                    PPP_IPCP,
+                    ControlPacket::new(TERM_ACK, packet.id, Vec::new()),
+                )
+                .await?;
+                Err(NativeError::Terminated(reason(&packet.data)))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The text a peer gave for terminating or failing authentication.
+fn reason(data: &[u8]) -> String {
+    let text = String::from_utf8_lossy(data).trim().to_string();
+    if text.is_empty() {
+        "no reason given".to_string()
+    } else {
+        text
+    }
+}
@@ -0,0 +1,169 @@
+//! PPPoE without pppd: discovery over a raw socket on the uplink, then LCP, PAP or
+//! CHAP-MD5 and IPCP over the kernel's PPPoE driver and `/dev/ppp`, the way pppd's
+//! PPPoE plugin does it. The resulting `pppN` interface is an ordinary kernel one.
+//!
+//! Needs the `pppoe` kernel module, `/dev/ppp` and `CAP_NET_ADMIN` plus `CAP_NET_RAW`.
+//! To try it without an ISP, run `pppoe-server` in a network namespace behind a veth
+//! pair and dial over the other end.
+
+mod discovery;
+mod kernel;
+mod link;
+mod packet;
+
+use log::info;
+use std::io;
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::pppoe::manager::PpmsEvent;
+use discovery::{Discovery, format_mac};
+use kernel::Channel;
+use link::Link;
+
+#[derive(Debug, Error)]
+pub enum NativeError {
+    #[error("Uplink {0} not found")]
+    UplinkNotFound(String),
+    #[error("No PADO received on {0}")]
+    NoOffer(String),
+    #[error("No PADS received from the access concentrator")]
+    NoConfirmation,
+    #[error("Access concentrator refused the session: {0}")]
+    Refused(String),
+    #[error("LCP negotiation timed out")]
+    LcpTimeout,
+    #[error("Peer did not answer authentication")]
+    AuthTimeout,
+    #[error("Authentication failed: {0}")]
+    AuthFailed(String),
+    #[error("IPCP negotiation timed out")]
+    IpcpTimeout,
+    #[error("Peer did not assign an IP address")]
+    NoAddress,
+    #[error("Peer restarted LCP negotiation")]
+    Renegotiated,
+    #[error("Peer stopped answering LCP echo requests")]
+    PeerNotResponding,
+    #[error("Peer terminated the link: {0}")]
+    Terminated(String),
+    #[error("Access concentrator ended the session with PADT")]
+    Padt,
+    #[error("Failed to {what}: {source}")]
+    Io {
+        what: &'static str,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Failed to {what}: {source}")]
+    Netlink {
+        what: String,
+        #[source]
+        source: rtnetlink::Error,
+    },
+}
+
+fn io_error(what: &'static str) -> impl FnOnce(io::Error) -> NativeError {
+    move |source| NativeError::Io { what, source }
+}
+
+/// Dials one session over `uplink` and keeps `interface` up until `stop` fires or the
+/// link fails, sending the same events the pppd client does. The access concentrator
+/// is sent a PADT either way.
+pub async fn run(
+    interface: String,
+    uplink: String,
+    username: String,
+    password: String,
+    event_sender: mpsc::Sender<PpmsEvent>,
+    mut stop: oneshot::Receiver<()>,
+) -> Result<(), NativeError> {
+    let discovery = Discovery::open(&uplink)?;
+    let session = tokio::select! {
+        session = discovery.discover() => session?,
+        _ = &mut stop => return Ok(()),
+    };
+    info!(
+        "{}: PPPoE session {} with {} ({})",
+        interface,
+        session.session_id,
+        format_mac(&session.peer),
+        session.ac_name.as_deref().unwrap_or("unnamed")
+    );
+
+    let result = match Channel::open(&uplink, &session) {
+        Ok(channel) => {
+            Link::new(
+                channel,
+                &session,
+                interface,
+                username,
+                password,
+                event_sender,
+            )
+            .run(&discovery, &session, &mut stop)
+            .await
+        }
+        Err(e) => Err(e),
+    };
+    if !matches!(result, Err(NativeError::Padt)) {
+        discovery.terminate(&session).await;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Dials a real `pppoe-server`. Needs root and rp-pppoe; set it up with
+    ///
+    /// ```sh
+    /// ip netns add pppoe-ac
+    /// ip link add veth-ac type veth peer name veth-dial
+    /// ip link set veth-ac netns pppoe-ac
+    /// ip -n pppoe-ac link set veth-ac up && ip link set veth-dial up
+    /// echo '"test" * "test" *' >> /etc/ppp/pap-secrets
+    /// ip netns exec pppoe-ac pppoe-server -I veth-ac -L 10.67.0.1 -R 10.67.0.2 -N 1
+    /// ```
+    ///
+    /// then run `cargo test -- --ignored dials_pppoe_server`. `PPPOE_TEST_UPLINK`,
+    /// `PPPOE_TEST_USER` and `PPPOE_TEST_PASSWORD` override the defaults above.
+    #[tokio::test]
+    #[ignore = "needs root and pppoe-server in a network namespace"]
+    async fn dials_pppoe_server() {
+        let env = |name: &str, default: &str| std::env::var(name).unwrap_or(default.to_string());
+        let (event_sender, mut events) = mpsc::channel(16);
+        let (stop_sender, stop) = oneshot::channel();
+        let session = tokio::spawn(run(
+            "ppp-test0".to_string(),
+            env("PPPOE_TEST_UPLINK", "veth-dial"),
+            env("PPPOE_TEST_USER", "test"),
+            env("PPPOE_TEST_PASSWORD", "test"),
+            event_sender,
+            stop,
+        ));
+
+        let local_ip = tokio::time::timeout(Duration::from_secs(30), async {
+            while let Some(event) = events.recv().await {
+                if let PpmsEvent::IpUpdated {
+                    local_ip: Some(ip), ..
+                } = event
+                {
+                    return Some(ip);
+                }
+            }
+            None
+        })
+        .await
+        .expect("no IP assigned within 30s");
+        assert!(
+            local_ip.is_some(),
+            "session ended before an IP was assigned"
+        );
+
+        stop_sender.send(()).unwrap();
+        session.await.unwrap().unwrap();
+    }
+}
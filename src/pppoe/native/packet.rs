@@ -0,0 +1,155 @@
+pub const PPP_IPCP: u16 = 0x8021;
+pub const PPP_LCP: u16 = 0xc021;
+pub const PPP_PAP: u16 = 0xc023;
+pub const PPP_CHAP: u16 = 0xc223;
+
+// LCP and IPCP codes; IPCP only uses the first seven.
+pub const CONF_REQ: u8 = 1;
+pub const CONF_ACK: u8 = 2;
+pub const CONF_NAK: u8 = 3;
+pub const CONF_REJ: u8 = 4;
+pub const TERM_REQ: u8 = 5;
+pub const TERM_ACK: u8 = 6;
+pub const PROTO_REJ: u8 = 8;
+pub const ECHO_REQ: u8 = 9;
+pub const ECHO_REPLY: u8 = 10;
+
+/// An LCP, IPCP, PAP or CHAP packet.
+#[derive(Debug)]
+pub struct ControlPacket {
+    pub code: u8,
+    pub id: u8,
+    pub data: Vec<u8>,
+}
+
+impl ControlPacket {
+    pub fn new(code: u8, id: u8, data: Vec<u8>) -> Self {
+        Self { code, id, data }
+    }
+
+    /// Parses the packet in a frame's information field, ignoring padding after it.
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 4 {
+            return None;
+        }
+        let length = usize::from(u16::from_be_bytes([buf[2], buf[3]]));
+        Some(Self {
+            code: buf[0],
+            id: buf[1],
+            data: buf.get(4..length)?.to_vec(),
+        })
+    }
+
+    /// A frame as `/dev/ppp` takes it: the protocol, then the packet.
+    pub fn frame(&self, protocol: u16) -> Vec<u8> {
+        let mut frame = protocol.to_be_bytes().to_vec();
+        frame.extend_from_slice(&[self.code, self.id]);
+        frame.extend_from_slice(&(self.data.len() as u16 + 4).to_be_bytes());
+        frame.extend_from_slice(&self.data);
+        frame
+    }
+}
+
+/// A configure option: its type and value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigOption {
+    pub kind: u8,
+    pub data: Vec<u8>,
+}
+
+impl ConfigOption {
+    pub fn new(kind: u8, data: impl Into<Vec<u8>>) -> Self {
+        Self {
+            kind,
+            data: data.into(),
+        }
+    }
+
+    /// `None` if the options do not add up to exactly `data`.
+    pub fn parse_all(mut data: &[u8]) -> Option<Vec<Self>> {
+        let mut options = Vec::new();
+        while !data.is_empty() {
+            let length = usize::from(*data.get(1)?);
+            if length < 2 {
+                return None;
+            }
+            options.push(Self::new(data[0], data.get(2..length)?));
+            data = &data[length..];
+        }
+        Some(options)
+    }
+
+    pub fn encode_all(options: &[Self]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for option in options {
+            data.push(option.kind);
+            data.push(option.data.len() as u8 + 2);
+            data.extend_from_slice(&option.data);
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An LCP Configure-Request for MRU 1492 and magic number 0x12345678, as pppd sends it.
+    const LCP_CONF_REQ: [u8; 16] = [
+        0xc0, 0x21, 0x01, 0x01, 0x00, 0x0e, 0x01, 0x04, 0x05, 0xd4, 0x05, 0x06, 0x12, 0x34, 0x56,
+        0x78,
+    ];
+
+    #[test]
+    fn encodes_lcp_configure_request() {
+        let options = [
+            ConfigOption::new(1, 1492u16.to_be_bytes()),
+            ConfigOption::new(5, 0x1234_5678u32.to_be_bytes()),
+        ];
+        let packet = ControlPacket::new(CONF_REQ, 1, ConfigOption::encode_all(&options));
+        assert_eq!(packet.frame(PPP_LCP), LCP_CONF_REQ);
+    }
+
+    #[test]
+    fn decodes_lcp_configure_request() {
+        let packet = ControlPacket::parse(&LCP_CONF_REQ[2..]).unwrap();
+        assert_eq!((packet.code, packet.id), (CONF_REQ, 1));
+        assert_eq!(
+            ConfigOption::parse_all(&packet.data).unwrap(),
+            [
+                ConfigOption::new(1, [0x05, 0xd4]),
+                ConfigOption::new(5, [0x12, 0x34, 0x56, 0x78]),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_padding_after_packet() {
+        // An Echo-Reply padded to the ethernet minimum.
+        let mut buf = vec![ECHO_REPLY, 7, 0x00, 0x08, 1, 2, 3, 4];
+        buf.resize(46, 0);
+        let packet = ControlPacket::parse(&buf).unwrap();
+        assert_eq!(packet.data, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        // Shorter than a header.
+        assert!(ControlPacket::parse(&[CONF_REQ, 1, 0x00]).is_none());
+        // Length past the end of the buffer.
+        assert!(ControlPacket::parse(&[CONF_REQ, 1, 0x00, 0x10, 1, 4]).is_none());
+        // Length shorter than the header itself.
+        assert!(ControlPacket::parse(&[CONF_REQ, 1, 0x00, 0x02]).is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_options() {
+        // An option claiming to be shorter than its own header.
+        assert!(ConfigOption::parse_all(&[1, 1, 0x05]).is_none());
+        // An option running past the end.
+        assert!(ConfigOption::parse_all(&[1, 4, 0x05]).is_none());
+        // A lone type byte.
+        assert!(ConfigOption::parse_all(&[1]).is_none());
+        assert_eq!(ConfigOption::parse_all(&[]), Some(Vec::new()));
+    }
+}
@@ -0,0 +1,326 @@
+use chrono::Local;
+use log::{info, warn};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use crate::core::config::SessionLogConfig;
+
+/// Lines waiting for the file writer; more are dropped from the file, not from memory.
+const WRITE_BACKLOG: usize = 4096;
+/// How long to keep a log in memory only after writing its file failed.
+const REOPEN_AFTER: Duration = Duration::from_secs(60);
+
+/// Where a captured line came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+    /// The client's own notes: dialing, why a connection ended, giving up.
+    Client,
+}
+
+impl std::fmt::Display for Stream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stream::Stdout => write!(f, "out"),
+            Stream::Stderr => write!(f, "err"),
+            Stream::Client => write!(f, "client"),
+        }
+    }
+}
+
+/// The recent output of one session's pppd, kept in memory and, with a log directory
+/// set, appended to `<interface>.log` there.
+pub struct SessionLog {
+    interface: String,
+    max_lines: usize,
+    lines: Mutex<VecDeque<String>>,
+    writer: Option<mpsc::Sender<(String, String)>>,
+    /// Set while the writer is behind, so dropped lines are only reported once.
+    dropping: AtomicBool,
+}
+
+impl SessionLog {
+    fn new(
+        interface: &str,
+        max_lines: usize,
+        writer: Option<mpsc::Sender<(String, String)>>,
+    ) -> Self {
+        Self {
+            interface: interface.to_string(),
+            max_lines,
+            lines: Mutex::new(VecDeque::new()),
+            writer,
+            dropping: AtomicBool::new(false),
+        }
+    }
+
+    pub fn push(&self, stream: Stream, line: &str) {
+        let line = format!(
+            "{} {:<6} {}",
+            Local::now().format("%m-%d %H:%M:%S"),
+            stream,
+            line.trim_end()
+        );
+        if let Some(writer) = &self.writer {
+            match writer.try_send((self.interface.clone(), line.clone())) {
+                Ok(()) => self.dropping.store(false, Ordering::Relaxed),
+                Err(_) => {
+                    if !self.dropping.swap(true, Ordering::Relaxed) {
+                        warn!(
+                            "{}: Session log file writer is behind, dropping lines from the file",
+                            self.interface
+                        );
+                    }
+                }
+            }
+        }
+        if self.max_lines == 0 {
+            return;
+        }
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == self.max_lines {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    /// The last `count` lines, oldest first.
+    pub fn tail(&self, count: usize) -> Vec<String> {
+        let lines = self.lines.lock().unwrap();
+        let skip = lines.len().saturating_sub(count);
+        lines.iter().skip(skip).cloned().collect()
+    }
+}
+
+/// Appends the lines of every session to their files, on a thread of its own so slow
+/// disks never hold up the runtime.
+struct FileWriter {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_files: u32,
+    files: HashMap<String, FileState>,
+}
+
+#[derive(Default)]
+struct FileState {
+    file: Option<LogFile>,
+    /// When writing last failed; the file is tried again [`REOPEN_AFTER`] later.
+    failed_at: Option<Instant>,
+}
+
+struct LogFile {
+    file: File,
+    size: u64,
+}
+
+impl FileWriter {
+    fn spawn(config: &SessionLogConfig) -> Option<mpsc::Sender<(String, String)>> {
+        let dir = config.dir.as_ref()?;
+        let (sender, mut receiver) = mpsc::channel::<(String, String)>(WRITE_BACKLOG);
+        let mut writer = FileWriter {
+            dir: PathBuf::from(dir),
+            max_bytes: config.max_bytes,
+            max_files: config.max_files,
+            files: HashMap::new(),
+        };
+        let spawned = std::thread::Builder::new()
+            .name("session-log".to_string())
+            .spawn(move || {
+                while let Some((interface, line)) = receiver.blocking_recv() {
+                    writer.push(&interface, &line);
+                }
+            });
+        match spawned {
+            Ok(_) => Some(sender),
+            Err(e) => {
+                warn!(
+                    "Failed to start the session log writer, keeping logs in memory only: {}",
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    fn push(&mut self, interface: &str, line: &str) {
+        let mut state = self.files.remove(interface).unwrap_or_default();
+        if state
+            .failed_at
+            .is_some_and(|at| at.elapsed() < REOPEN_AFTER)
+        {
+            self.files.insert(interface.to_string(), state);
+            return;
+        }
+        match self.write(interface, &mut state.file, line) {
+            Ok(()) => {
+                if state.failed_at.take().is_some() {
+                    info!(
+                        "{}: Writing the session log to {} again",
+                        interface,
+                        self.dir.display()
+                    );
+                }
+            }
+            Err(e) => {
+                if state.failed_at.is_none() {
+                    warn!(
+                        "{}: Failed to write the session log to {}, keeping it in memory only for now: {}",
+                        interface,
+                        self.dir.display(),
+                        e
+                    );
+                }
+                state.file = None;
+                state.failed_at = Some(Instant::now());
+            }
+        }
+        self.files.insert(interface.to_string(), state);
+    }
+
+    fn path(&self, interface: &str, generation: u32) -> PathBuf {
+        match generation {
+            0 => self.dir.join(format!("{}.log", interface)),
+            n => self.dir.join(format!("{}.log.{}", interface, n)),
+        }
+    }
+
+    fn write(&self, interface: &str, file: &mut Option<LogFile>, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if file
+            .as_ref()
+            .is_some_and(|file| file.size > 0 && file.size + len > self.max_bytes)
+        {
+            *file = None;
+            self.rotate(interface)?;
+        }
+        let file = match file {
+            Some(file) => file,
+            None => {
+                fs::create_dir_all(&self.dir)?;
+                let opened = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.path(interface, 0))?;
+                let size = opened.metadata()?.len();
+                file.insert(LogFile { file: opened, size })
+            }
+        };
+        writeln!(file.file, "{}", line)?;
+        file.size += len;
+        Ok(())
+    }
+
+    /// Shifts `<interface>.log.N` to `.N+1`, dropping the oldest, and the current file
+    /// to `.1`.
+    fn rotate(&self, interface: &str) -> io::Result<()> {
+        let renamed = |from: PathBuf, to: PathBuf| match fs::rename(from, to) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+        if self.max_files == 0 {
+            return fs::remove_file(self.path(interface, 0));
+        }
+        for generation in (1..self.max_files).rev() {
+            renamed(
+                self.path(interface, generation),
+                self.path(interface, generation + 1),
+            )?;
+        }
+        renamed(self.path(interface, 0), self.path(interface, 1))
+    }
+}
+
+/// The log of every session started since launch, kept after a session is removed so
+/// its last words can still be read.
+pub struct SessionLogs {
+    config: SessionLogConfig,
+    writer: Option<mpsc::Sender<(String, String)>>,
+    logs: Mutex<BTreeMap<String, Arc<SessionLog>>>,
+}
+
+impl SessionLogs {
+    pub fn new(config: SessionLogConfig) -> Self {
+        Self {
+            writer: FileWriter::spawn(&config),
+            config,
+            logs: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The log of `interface`, created on first use.
+    pub fn get(&self, interface: &str) -> Arc<SessionLog> {
+        let mut logs = self.logs.lock().unwrap();
+        let log = logs.entry(interface.to_string()).or_insert_with(|| {
+            Arc::new(SessionLog::new(
+                interface,
+                self.config.lines,
+                self.writer.clone(),
+            ))
+        });
+        Arc::clone(log)
+    }
+
+    /// The last `count` lines of `interface`, or `None` if it never ran.
+    pub fn tail(&self, interface: &str, count: usize) -> Option<Vec<String>> {
+        let logs = self.logs.lock().unwrap();
+        logs.get(interface).map(|log| log.tail(count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn writer(name: &str, max_bytes: u64) -> FileWriter {
+        let dir = std::env::temp_dir().join(format!("ppproxy-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        FileWriter {
+            dir,
+            max_bytes,
+            max_files: 2,
+            files: HashMap::new(),
+        }
+    }
+
+    fn read(writer: &FileWriter, generation: u32) -> String {
+        fs::read_to_string(writer.path("ppp0", generation)).unwrap_or_default()
+    }
+
+    #[test]
+    fn rotates_full_files() {
+        let mut writer = writer("rotate", 12);
+        for line in ["one", "two", "three", "four", "five"] {
+            writer.push("ppp0", line);
+        }
+        assert_eq!(read(&writer, 0), "five\n");
+        assert_eq!(read(&writer, 1), "three\nfour\n");
+        assert_eq!(read(&writer, 2), "one\ntwo\n");
+        fs::remove_dir_all(&writer.dir).unwrap();
+    }
+
+    #[test]
+    fn reopens_after_failure() {
+        let mut writer = writer("reopen", 1024);
+        // A file where the directory should be makes opening the log fail.
+        fs::write(&writer.dir, "").unwrap();
+        writer.push("ppp0", "lost");
+        assert!(writer.files["ppp0"].failed_at.is_some());
+
+        fs::remove_file(&writer.dir).unwrap();
+        writer.push("ppp0", "skipped while failed");
+        assert_eq!(read(&writer, 0), "");
+
+        writer.files.get_mut("ppp0").unwrap().failed_at = Some(Instant::now() - REOPEN_AFTER);
+        writer.push("ppp0", "back");
+        assert_eq!(read(&writer, 0), "back\n");
+        assert!(writer.files["ppp0"].failed_at.is_none());
+        fs::remove_dir_all(&writer.dir).unwrap();
+    }
+}
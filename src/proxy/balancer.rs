@@ -0,0 +1,49 @@
+use anyhow::{Result, anyhow};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::core::config::BalanceStrategy;
+use crate::pppoe::manager::PPPoEManager;
+
+/// Spreads new connections across the sessions that are currently available.
+#[derive(Debug)]
+pub struct Balancer {
+    strategy: BalanceStrategy,
+    next: AtomicUsize,
+}
+
+impl Balancer {
+    pub fn new(strategy: BalanceStrategy) -> Self {
+        Self {
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub async fn select(&self, manager: &PPPoEManager) -> Result<String> {
+        let available: Vec<_> = manager
+            .get_all_stats()
+            .await
+            .into_iter()
+            .filter(|(_, info)| info.is_available())
+            .collect();
+        if available.is_empty() {
+            return Err(anyhow!("No available sessions"));
+        }
+
+        let (interface, _) = match self.strategy {
+            BalanceStrategy::RoundRobin => {
+                let i = self.next.fetch_add(1, Ordering::Relaxed) % available.len();
+                &available[i]
+            }
+            BalanceStrategy::LeastConnections => available
+                .iter()
+                .min_by_key(|(_, info)| info.active_connections)
+                .unwrap(),
+            BalanceStrategy::LeastBandwidth => available
+                .iter()
+                .min_by_key(|(_, info)| info.send_rate_bps + info.receive_rate_bps)
+                .unwrap(),
+        };
+        Ok(interface.clone())
+    }
+}
@@ -0,0 +1,218 @@
+use anyhow::{Result, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use log::trace;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::proxy::listener::{ClientRequest, Router, TargetAddr, connect_via};
+
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Headers that only concern the hop between client and proxy.
+const HOP_HEADERS: [&str; 4] = [
+    "proxy-authorization",
+    "proxy-connection",
+    "connection",
+    "keep-alive",
+];
+
+struct RequestHead {
+    method: String,
+    target: String,
+    version: String,
+    headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    fn parse(head: &str) -> Result<Self> {
+        let mut lines = head.split("\r\n");
+        let request_line = lines.next().ok_or_else(|| anyhow!("Empty request"))?;
+        let mut parts = request_line.split_whitespace();
+        let (Some(method), Some(target), Some(version)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(anyhow!("Malformed request line: {}", request_line));
+        };
+
+        let headers = lines
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+
+        Ok(Self {
+            method: method.to_string(),
+            target: target.to_string(),
+            version: version.to_string(),
+            headers,
+        })
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Username from a `Proxy-Authorization: Basic` header.
+    fn username(&self) -> Option<String> {
+        let value = self.header("Proxy-Authorization")?;
+        let (scheme, encoded) = value.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = BASE64.decode(encoded.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (username, _password) = decoded.split_once(':')?;
+        Some(username.to_string())
+    }
+}
+
+fn parse_authority(authority: &str, default_port: u16) -> Result<TargetAddr> {
+    let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+        let (host, rest) = rest
+            .split_once(']')
+            .ok_or_else(|| anyhow!("Invalid authority: {}", authority))?;
+        match rest.strip_prefix(':') {
+            Some(port) => (host, port.parse()?),
+            None => (host, default_port),
+        }
+    } else {
+        match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse()?),
+            None => (authority, default_port),
+        }
+    };
+    Ok(match host.parse::<IpAddr>() {
+        Ok(ip) => TargetAddr::Ip(SocketAddr::new(ip, port)),
+        Err(_) => TargetAddr::Domain(host.to_string(), port),
+    })
+}
+
+/// Reads up to the end of the request head, returning it and any bytes read past it.
+async fn read_head(stream: &mut TcpStream) -> Result<(String, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(4096);
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(anyhow!("Connection closed before request head"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let leftover = buf.split_off(end + 4);
+            return Ok((String::from_utf8_lossy(&buf).into_owned(), leftover));
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            return Err(anyhow!("Request head too large"));
+        }
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &str) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Serves one HTTP proxy client, supporting CONNECT tunnels and plain `http://` requests.
+pub async fn serve(mut stream: TcpStream, peer: SocketAddr, router: &Router) -> Result<()> {
+    let (head, leftover) = read_head(&mut stream).await?;
+    let request = RequestHead::parse(&head)?;
+
+    let username = request.username();
+    let client = ClientRequest {
+        peer,
+        username: username.as_deref(),
+        headers: &request.headers,
+    };
+    let interface = match router.resolve(&client).await {
+        Ok(interface) => interface,
+        Err(e) => {
+            respond(&mut stream, "503 Service Unavailable", &e.to_string()).await?;
+            return Err(e);
+        }
+    };
+
+    let is_connect = request.method.eq_ignore_ascii_case("CONNECT");
+    let (target, path) = if is_connect {
+        (parse_authority(&request.target, 443), None)
+    } else if let Some(rest) = request.target.strip_prefix("http://") {
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        (parse_authority(authority, 80), Some(path.to_string()))
+    } else {
+        respond(
+            &mut stream,
+            "400 Bad Request",
+            "Absolute http:// URI required",
+        )
+        .await?;
+        return Err(anyhow!("Unsupported request target: {}", request.target));
+    };
+    let target = match target {
+        Ok(target) => target,
+        Err(e) => {
+            respond(&mut stream, "400 Bad Request", &e.to_string()).await?;
+            return Err(e);
+        }
+    };
+    trace!("{}: HTTP {} {}", interface, request.method, target);
+
+    let _connection = router.manager().connection_opened(&interface).await;
+    relay(stream, &interface, &request, target, path, leftover).await
+}
+
+async fn relay(
+    mut stream: TcpStream,
+    interface: &str,
+    request: &RequestHead,
+    target: TargetAddr,
+    path: Option<String>,
+    leftover: Vec<u8>,
+) -> Result<()> {
+    let result = match target.resolve().await {
+        Ok(addr) => connect_via(interface, addr).await,
+        Err(e) => Err(e),
+    };
+    let mut remote = match result {
+        Ok(remote) => remote,
+        Err(e) => {
+            respond(&mut stream, "502 Bad Gateway", &e.to_string()).await?;
+            return Err(anyhow!("Failed to connect to {}: {}", target, e));
+        }
+    };
+
+    match path {
+        None => {
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await?;
+        }
+        Some(path) => {
+            // Forward in origin form and close afterwards so the next request is routed again.
+            let mut head = format!("{} {} {}\r\n", request.method, path, request.version);
+            for (name, value) in &request.headers {
+                if !HOP_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+                    head.push_str(&format!("{}: {}\r\n", name, value));
+                }
+            }
+            head.push_str("Connection: close\r\n\r\n");
+            remote.write_all(head.as_bytes()).await?;
+        }
+    }
+    remote.write_all(&leftover).await?;
+
+    tokio::io::copy_bidirectional(&mut stream, &mut remote).await?;
+    Ok(())
+}
@@ -0,0 +1,335 @@
+use anyhow::Result;
+use log::{debug, error, info, warn};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{Mutex, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, timeout};
+
+use crate::core::config::{PROXY_PORT_BASE, ProxyConfig, StickyKey};
+use crate::pppoe::manager::PPPoEManager;
+use crate::proxy::balancer::Balancer;
+use crate::proxy::selector::SessionSelector;
+use crate::proxy::sticky::{StickyLookup, StickyTable};
+use crate::proxy::{http, socks5};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause after a failed accept, e.g. when out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Destination requested by a proxy client.
+#[derive(Debug, Clone)]
+pub enum TargetAddr {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl TargetAddr {
+    /// Resolves the target, preferring IPv4 since PPPoE sessions are IPv4 only.
+    pub async fn resolve(&self) -> io::Result<SocketAddr> {
+        match self {
+            TargetAddr::Ip(addr) => Ok(*addr),
+            TargetAddr::Domain(host, port) => {
+                let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), *port))
+                    .await?
+                    .collect();
+                addrs
+                    .iter()
+                    .find(|a| a.is_ipv4())
+                    .or_else(|| addrs.first())
+                    .copied()
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, format!("{host} not resolved"))
+                    })
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TargetAddr::Ip(addr) => write!(f, "{addr}"),
+            TargetAddr::Domain(host, port) => write!(f, "{host}:{port}"),
+        }
+    }
+}
+
+/// Opens a TCP connection to `addr` whose socket is pinned to `interface` via SO_BINDTODEVICE.
+pub async fn connect_via(interface: &str, addr: SocketAddr) -> io::Result<TcpStream> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.bind_device(Some(interface.as_bytes()))?;
+    timeout(CONNECT_TIMEOUT, socket.connect(addr))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))?
+}
+
+/// How a listener chooses the interface outbound connections leave through.
+#[derive(Debug, Clone)]
+pub enum Egress {
+    Fixed(String),
+    /// Picked per connection from the SOCKS5/HTTP auth username.
+    Selected,
+    /// Spread across available sessions by the listener's balancer.
+    Balanced(Arc<Balancer>),
+}
+
+/// What a client revealed before its egress is chosen.
+pub struct ClientRequest<'a> {
+    pub peer: SocketAddr,
+    pub username: Option<&'a str>,
+    pub headers: &'a [(String, String)],
+}
+
+/// Resolves the egress interface for each connection accepted by one listener.
+pub struct Router {
+    manager: Arc<PPPoEManager>,
+    egress: Egress,
+    sticky: Arc<StickyTable>,
+    sticky_key: StickyKey,
+}
+
+impl Router {
+    pub fn manager(&self) -> &Arc<PPPoEManager> {
+        &self.manager
+    }
+
+    /// Whether clients should be asked for a username during the handshake.
+    pub fn wants_username(&self) -> bool {
+        match &self.egress {
+            Egress::Selected => true,
+            Egress::Balanced(_) => self.sticky_key == StickyKey::Username,
+            Egress::Fixed(_) => false,
+        }
+    }
+
+    pub async fn resolve(&self, request: &ClientRequest<'_>) -> Result<String> {
+        match &self.egress {
+            Egress::Fixed(interface) => Ok(interface.clone()),
+            Egress::Selected => {
+                let selector = match request.username {
+                    Some(name) => name.parse()?,
+                    None => SessionSelector::Random,
+                };
+                // Only `sticky-<key>` asks for stickiness: a pin, an account or `random`
+                // must not be answered with a session cached for another request.
+                if let SessionSelector::Sticky(_) = selector {
+                    return self
+                        .resolve_sticky(request, selector.select(&self.manager))
+                        .await;
+                }
+                selector.select(&self.manager).await
+            }
+            Egress::Balanced(balancer) => {
+                self.resolve_sticky(request, balancer.select(&self.manager))
+                    .await
+            }
+        }
+    }
+
+    fn sticky_key(&self, request: &ClientRequest<'_>) -> Option<String> {
+        match &self.sticky_key {
+            StickyKey::Username => request
+                .username
+                .and_then(|name| name.strip_prefix("sticky-"))
+                .filter(|key| !key.is_empty())
+                .map(|key| format!("user:{key}")),
+            StickyKey::SourceIp => Some(format!("ip:{}", request.peer.ip())),
+            StickyKey::Header(name) => request
+                .headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| format!("header:{value}")),
+        }
+    }
+
+    async fn resolve_sticky(
+        &self,
+        request: &ClientRequest<'_>,
+        select: impl Future<Output = Result<String>>,
+    ) -> Result<String> {
+        let Some(key) = self.sticky_key(request) else {
+            return select.await;
+        };
+        let stats = self.manager.get_all_stats().await;
+        match self.sticky.lookup(&key, &stats).await? {
+            StickyLookup::Mapped(interface) => return Ok(interface),
+            StickyLookup::Unavailable => return select.await,
+            StickyLookup::Unmapped => {}
+        }
+        let interface = select.await?;
+        let local_ip = stats.get(&interface).and_then(|info| info.local_ip.clone());
+        self.sticky.insert(key, interface.clone(), local_ip).await;
+        Ok(interface)
+    }
+}
+
+/// Native SOCKS5/HTTP proxy listeners, keyed by port.
+pub struct ProxyListeners {
+    manager: Arc<PPPoEManager>,
+    sticky: Arc<StickyTable>,
+    sticky_key: StickyKey,
+    tasks: Mutex<BTreeMap<u16, JoinHandle<()>>>,
+    /// Connections accepted and not yet finished, over all listeners.
+    open: Arc<watch::Sender<usize>>,
+}
+
+/// Counts an accepted connection in [`ProxyListeners::open`] until its handler ends.
+struct OpenConnection(Arc<watch::Sender<usize>>);
+
+impl OpenConnection {
+    fn new(open: &Arc<watch::Sender<usize>>) -> Self {
+        open.send_modify(|count| *count += 1);
+        Self(Arc::clone(open))
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.send_modify(|count| *count -= 1);
+    }
+}
+
+impl ProxyListeners {
+    pub fn new(manager: Arc<PPPoEManager>, config: &ProxyConfig) -> Arc<Self> {
+        let sticky = StickyTable::new(
+            Duration::from_secs(config.sticky_ttl_secs),
+            config.sticky_on_ip_change,
+        );
+        Arc::new(Self {
+            manager,
+            sticky: Arc::new(sticky),
+            sticky_key: config.sticky_key.clone(),
+            tasks: Mutex::new(BTreeMap::new()),
+            open: Arc::new(watch::Sender::new(0)),
+        })
+    }
+
+    /// Listens on [`PROXY_PORT_BASE`] for `wan`, on the ports above it for every PPPoE
+    /// session and on the selector and balancer ports.
+    pub async fn start(listeners: Arc<Self>, wan: &str, session_count: u16, config: &ProxyConfig) {
+        listeners
+            .listen(PROXY_PORT_BASE, Egress::Fixed(wan.to_string()))
+            .await;
+        listeners.resize(0, session_count).await;
+        if config.selector_port != 0 {
+            listeners
+                .listen(config.selector_port, Egress::Selected)
+                .await;
+        }
+        if config.balancer_port != 0 {
+            let balancer = Balancer::new(config.balance_strategy);
+            listeners
+                .listen(config.balancer_port, Egress::Balanced(Arc::new(balancer)))
+                .await;
+        }
+    }
+
+    /// Opens the session ports added and closes those removed when the session count
+    /// changes from `from` to `to`.
+    pub async fn resize(self: &Arc<Self>, from: u16, to: u16) {
+        for i in from..to {
+            self.listen(PROXY_PORT_BASE + i + 1, Egress::Fixed(format!("ppp{}", i)))
+                .await;
+        }
+        for i in to..from {
+            self.close(PROXY_PORT_BASE + i + 1).await;
+        }
+    }
+
+    pub async fn listen(self: &Arc<Self>, port: u16, egress: Egress) {
+        let listener = match TcpListener::bind(("0.0.0.0", port)).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to bind proxy listener on :{}: {}", port, e);
+                return;
+            }
+        };
+        info!("Proxy listener on :{} -> {:?}", port, egress);
+
+        let router = Arc::new(Router {
+            manager: Arc::clone(&self.manager),
+            egress,
+            sticky: Arc::clone(&self.sticky),
+            sticky_key: self.sticky_key.clone(),
+        });
+        let open = Arc::clone(&self.open);
+        let task = tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        error!("Failed to accept on :{}: {}", port, e);
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                };
+                let router = Arc::clone(&router);
+                let connection = OpenConnection::new(&open);
+                tokio::spawn(async move {
+                    let _connection = connection;
+                    if let Err(e) = handle_client(stream, peer, &router).await {
+                        debug!(":{}: proxy session from {} ended: {}", port, peer, e);
+                    }
+                });
+            }
+        });
+        if let Some(old) = self.tasks.lock().await.insert(port, task) {
+            old.abort();
+        }
+    }
+
+    /// Stops accepting on `port`; connections already accepted run to completion.
+    pub async fn close(&self, port: u16) {
+        if let Some(task) = self.tasks.lock().await.remove(&port) {
+            task.abort();
+            info!("Proxy listener on :{} closed", port);
+        }
+    }
+
+    pub async fn stop(&self) {
+        for (_, task) in std::mem::take(&mut *self.tasks.lock().await) {
+            task.abort();
+        }
+        debug!("Proxy listeners stopped");
+    }
+
+    /// Waits up to `grace` for the connections already accepted to finish.
+    pub async fn drain(&self, grace: Duration) {
+        let mut open = self.open.subscribe();
+        let count = *open.borrow();
+        if count == 0 {
+            return;
+        }
+        info!("Waiting for {} proxy connection(s) to finish", count);
+        if timeout(grace, open.wait_for(|count| *count == 0))
+            .await
+            .is_err()
+        {
+            warn!(
+                "{} proxy connection(s) still open after {:?}, cutting them off",
+                *open.borrow(),
+                grace
+            );
+        }
+    }
+}
+
+async fn handle_client(stream: TcpStream, peer: SocketAddr, router: &Router) -> Result<()> {
+    let mut first = [0u8; 1];
+    if stream.peek(&mut first).await? == 0 {
+        return Ok(());
+    }
+    if first[0] == socks5::SOCKS_VERSION {
+        socks5::serve(stream, peer, router).await
+    } else {
+        http::serve(stream, peer, router).await
+    }
+}
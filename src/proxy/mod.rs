@@ -1 +1,7 @@
+pub mod balancer;
+pub mod http;
+pub mod listener;
+pub mod selector;
 pub mod server;
+pub mod socks5;
+pub mod sticky;
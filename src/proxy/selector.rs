@@ -0,0 +1,92 @@
+use anyhow::{Error, Result, anyhow};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+use crate::pppoe::manager::PPPoEManager;
+
+/// Egress choice encoded in a proxy auth username.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionSelector {
+    /// `session-3` pins the connection to ppp3.
+    Session(String),
+    /// `random` picks any available session.
+    Random,
+    /// `account-<name>` picks any available session of that account.
+    Account(String),
+    /// `sticky-<key>` keeps a key on the same session while it stays available.
+    Sticky(String),
+}
+
+impl FromStr for SessionSelector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "random" {
+            return Ok(SessionSelector::Random);
+        }
+        if let Some(index) = s.strip_prefix("session-") {
+            let index: u16 = index
+                .parse()
+                .map_err(|_| anyhow!("Invalid session index in username: {}", s))?;
+            return Ok(SessionSelector::Session(format!("ppp{}", index)));
+        }
+        if let Some(name) = s.strip_prefix("account-")
+            && !name.is_empty()
+        {
+            return Ok(SessionSelector::Account(name.to_string()));
+        }
+        if let Some(key) = s.strip_prefix("sticky-")
+            && !key.is_empty()
+        {
+            return Ok(SessionSelector::Sticky(key.to_string()));
+        }
+        Err(anyhow!("Unknown session selector: {}", s))
+    }
+}
+
+impl SessionSelector {
+    pub async fn select(&self, manager: &PPPoEManager) -> Result<String> {
+        let stats = manager.get_all_stats().await;
+        let available: Vec<String> = stats
+            .iter()
+            .filter(|(_, info)| info.is_available())
+            .map(|(interface, _)| interface.clone())
+            .collect();
+
+        match self {
+            SessionSelector::Session(interface) => {
+                if available.contains(interface) {
+                    Ok(interface.clone())
+                } else {
+                    Err(anyhow!("{} is not connected or unhealthy", interface))
+                }
+            }
+            SessionSelector::Random => {
+                if available.is_empty() {
+                    return Err(anyhow!("No available sessions"));
+                }
+                Ok(available[fastrand::usize(..available.len())].clone())
+            }
+            SessionSelector::Account(name) => {
+                let of_account: Vec<&String> = available
+                    .iter()
+                    .filter(|interface| stats[*interface].account == *name)
+                    .collect();
+                if of_account.is_empty() {
+                    return Err(anyhow!("No available sessions of account {}", name));
+                }
+                Ok(of_account[fastrand::usize(..of_account.len())].clone())
+            }
+            // Rendezvous hashing keeps a key on its session when others come and go.
+            SessionSelector::Sticky(key) => available
+                .into_iter()
+                .max_by_key(|interface| {
+                    let mut hasher = DefaultHasher::new();
+                    (key, interface).hash(&mut hasher);
+                    hasher.finish()
+                })
+                .ok_or_else(|| anyhow!("No available sessions")),
+        }
+    }
+}
@@ -8,6 +8,8 @@ use tokio::process::{Child, Command};
 use tokio::sync::Mutex;
 use tokio::task::JoinHandle;
 
+use crate::core::config::{GOST_API_PORT, GOST_METRICS_PORT, TUN_PORT_BASE};
+
 #[derive(Serialize)]
 struct GostConfig {
     services: Vec<Service>,
@@ -25,8 +27,6 @@ struct Service {
     bypass: Option<String>,
     handler: Handler,
     listener: Listener,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    metadata: Option<HashMap<String, String>>,
 }
 
 #[derive(Serialize)]
@@ -77,30 +77,6 @@ fn respawn_proxy_server(proxy: Arc<Mutex<ProxyServer>>) {
     tokio::spawn(Box::pin(ProxyServer::start(proxy)));
 }
 
-fn proxy_service(index: u16, interface: &str) -> Service {
-    let mut handler_metadata = HashMap::new();
-    handler_metadata.insert("udp".to_string(), serde_json::json!("true"));
-    handler_metadata.insert("udpBufferSize".to_string(), serde_json::json!("65565"));
-
-    let mut service_metadata = HashMap::new();
-    service_metadata.insert("interface".to_string(), interface.to_string());
-
-    Service {
-        name: format!("if{}-proxy", index),
-        addr: format!(":{}", 8080 + index),
-        bypass: Some("local-bypass".to_string()),
-        handler: Handler {
-            handler_type: "auto".to_string(),
-            metadata: Some(handler_metadata),
-        },
-        listener: Listener {
-            listener_type: "tcp".to_string(),
-            metadata: None,
-        },
-        metadata: Some(service_metadata),
-    }
-}
-
 fn tun_service(index: u16, interface: &str) -> Service {
     let mut handler_metadata = HashMap::new();
     handler_metadata.insert("bufferSize".to_string(), serde_json::json!(65535));
@@ -111,7 +87,7 @@ fn tun_service(index: u16, interface: &str) -> Service {
 
     Service {
         name: format!("if{}-tun", index),
-        addr: format!(":{}", 8880 + index),
+        addr: format!(":{}", TUN_PORT_BASE + index),
         bypass: Some("local-bypass".to_string()),
         handler: Handler {
             handler_type: "tun".to_string(),
@@ -121,50 +97,51 @@ fn tun_service(index: u16, interface: &str) -> Service {
             listener_type: "tun".to_string(),
             metadata: Some(listener_metadata),
         },
-        metadata: None,
     }
 }
 
-impl ProxyServer {
-    pub fn new(session_count: u16, logger_level: String) -> Arc<Mutex<Self>> {
-        let bypass = Bypass {
-            name: "local-bypass".to_string(),
-            matchers: vec![
-                "127.0.0.1/8".to_string(),
-                "10.0.0.0/8".to_string(),
-                "172.16.0.0/12".to_string(),
-                "192.168.0.0/16".to_string(),
-                "::1/128".to_string(),
-                "fc00::/7".to_string(),
-            ],
-        };
-
-        let mut services = Vec::new();
-
-        services.push(proxy_service(0, "eth0"));
-        services.push(tun_service(0, "tun0"));
-
-        for i in 0..session_count {
-            services.push(proxy_service(i + 1, &format!("ppp{}", i)));
-            services.push(tun_service(i + 1, &format!("tun{}", i + 1)));
-        }
+fn gost_config(session_count: u16, logger_level: String) -> String {
+    let bypass = Bypass {
+        name: "local-bypass".to_string(),
+        matchers: vec![
+            "127.0.0.1/8".to_string(),
+            "10.0.0.0/8".to_string(),
+            "172.16.0.0/12".to_string(),
+            "192.168.0.0/16".to_string(),
+            "::1/128".to_string(),
+            "fc00::/7".to_string(),
+        ],
+    };
+
+    let mut services = Vec::new();
+
+    // SOCKS5 is served natively by `ProxyListeners`; gost only provides the tun services.
+    services.push(tun_service(0, "tun0"));
+
+    for i in 0..session_count {
+        services.push(tun_service(i + 1, &format!("tun{}", i + 1)));
+    }
 
-        let config = GostConfig {
-            services,
-            bypasses: vec![bypass],
-            api: ApiConfig {
-                addr: ":18080".to_string(),
-            },
-            metrics: MetricsConfig {
-                addr: ":9000".to_string(),
-            },
-            log: LogConfig {
-                format: "text".to_string(),
-                level: logger_level,
-            },
-        };
-        let config_json = serde_json::to_string(&config).expect("Failed to serialize config");
+    let config = GostConfig {
+        services,
+        bypasses: vec![bypass],
+        api: ApiConfig {
+            addr: format!(":{}", GOST_API_PORT),
+        },
+        metrics: MetricsConfig {
+            addr: format!(":{}", GOST_METRICS_PORT),
+        },
+        log: LogConfig {
+            format: "text".to_string(),
+            level: logger_level,
+        },
+    };
+    serde_json::to_string(&config).expect("Failed to serialize config")
+}
 
+impl ProxyServer {
+    pub fn new(session_count: u16, logger_level: String) -> Arc<Mutex<Self>> {
+        let config_json = gost_config(session_count, logger_level);
         Arc::new(Mutex::new(Self {
             process: None,
             config_json,
@@ -186,6 +163,8 @@ impl ProxyServer {
             .arg(&p.config_json)
             .stdout(stdio())
             .stderr(stdio())
+            // `stop` aborts the guard that owns the child; take gost and its tuns down with it.
+            .kill_on_drop(true)
             .spawn()
             .expect("Failed to start proxy");
 
@@ -211,6 +190,25 @@ impl ProxyServer {
         }
     }
 
+    /// Restarts gost if its config changed. Returns whether it did.
+    ///
+    /// The PPPoE sessions stay up; only traffic through the tun services is interrupted.
+    pub async fn reconfigure(
+        proxy: Arc<Mutex<Self>>,
+        session_count: u16,
+        logger_level: String,
+    ) -> bool {
+        let config_json = gost_config(session_count, logger_level);
+        if proxy.lock().await.config_json == config_json {
+            return false;
+        }
+        info!("Proxy config changed, restarting proxy service");
+        ProxyServer::stop(Arc::clone(&proxy)).await;
+        proxy.lock().await.config_json = config_json;
+        ProxyServer::start(proxy).await;
+        true
+    }
+
     pub async fn stop(proxy: Arc<Mutex<Self>>) {
         let mut p = proxy.lock().await;
         if let Some(guard) = p.guard_task.take() {
@@ -0,0 +1,356 @@
+use anyhow::{Result, anyhow};
+use log::{debug, trace};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+use tokio::time::{Duration, timeout};
+
+use crate::pppoe::manager::PPPoEManager;
+use crate::proxy::listener::{ClientRequest, Router, TargetAddr, connect_via};
+
+pub const SOCKS_VERSION: u8 = 0x05;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xFF;
+
+const USER_PASS_VERSION: u8 = 0x01;
+const AUTH_SUCCEEDED: u8 = 0x00;
+const AUTH_FAILED: u8 = 0x01;
+
+const CMD_CONNECT: u8 = 0x01;
+const CMD_BIND: u8 = 0x02;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_GENERAL_FAILURE: u8 = 0x01;
+const REP_NETWORK_UNREACHABLE: u8 = 0x03;
+const REP_HOST_UNREACHABLE: u8 = 0x04;
+const REP_CONNECTION_REFUSED: u8 = 0x05;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REP_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+const BIND_ACCEPT_TIMEOUT: Duration = Duration::from_secs(120);
+const UDP_BUFFER_SIZE: usize = 65535;
+
+fn write_socket_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+async fn read_target_addr<R: AsyncReadExt + Unpin>(reader: &mut R, atyp: u8) -> Result<TargetAddr> {
+    let target = match atyp {
+        ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            reader.read_exact(&mut ip).await?;
+            let port = reader.read_u16().await?;
+            TargetAddr::Ip(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            reader.read_exact(&mut ip).await?;
+            let port = reader.read_u16().await?;
+            TargetAddr::Ip(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+        }
+        ATYP_DOMAIN => {
+            let len = reader.read_u8().await? as usize;
+            let mut host = vec![0u8; len];
+            reader.read_exact(&mut host).await?;
+            let port = reader.read_u16().await?;
+            TargetAddr::Domain(String::from_utf8_lossy(&host).into_owned(), port)
+        }
+        other => return Err(anyhow!("Unsupported address type: {other:#04x}")),
+    };
+    Ok(target)
+}
+
+/// Parses the header of a SOCKS5 UDP datagram, returning the target and payload offset.
+fn parse_udp_header(packet: &[u8]) -> Option<(TargetAddr, usize)> {
+    // RSV(2) FRAG(1) ATYP(1)
+    if packet.len() < 4 || packet[2] != 0 {
+        return None;
+    }
+    let rest = &packet[4..];
+    let (target, len) = match packet[3] {
+        ATYP_IPV4 if rest.len() >= 6 => {
+            let ip = Ipv4Addr::new(rest[0], rest[1], rest[2], rest[3]);
+            let port = u16::from_be_bytes([rest[4], rest[5]]);
+            (TargetAddr::Ip(SocketAddr::new(ip.into(), port)), 6)
+        }
+        ATYP_IPV6 if rest.len() >= 18 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&rest[..16]);
+            let port = u16::from_be_bytes([rest[16], rest[17]]);
+            (
+                TargetAddr::Ip(SocketAddr::new(Ipv6Addr::from(ip).into(), port)),
+                18,
+            )
+        }
+        ATYP_DOMAIN if !rest.is_empty() => {
+            let host_len = rest[0] as usize;
+            if rest.len() < 1 + host_len + 2 {
+                return None;
+            }
+            let host = String::from_utf8_lossy(&rest[1..1 + host_len]).into_owned();
+            let port = u16::from_be_bytes([rest[1 + host_len], rest[2 + host_len]]);
+            (TargetAddr::Domain(host, port), 1 + host_len + 2)
+        }
+        _ => return None,
+    };
+    Some((target, 4 + len))
+}
+
+fn reply_code(err: &io::Error) -> u8 {
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused => REP_CONNECTION_REFUSED,
+        io::ErrorKind::NetworkUnreachable => REP_NETWORK_UNREACHABLE,
+        io::ErrorKind::HostUnreachable | io::ErrorKind::TimedOut | io::ErrorKind::NotFound => {
+            REP_HOST_UNREACHABLE
+        }
+        _ => REP_GENERAL_FAILURE,
+    }
+}
+
+async fn send_reply(stream: &mut TcpStream, rep: u8, bound: SocketAddr) -> io::Result<()> {
+    let mut buf = vec![SOCKS_VERSION, rep, 0x00];
+    write_socket_addr(&mut buf, bound);
+    stream.write_all(&buf).await
+}
+
+fn unspecified_addr() -> SocketAddr {
+    SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
+}
+
+async fn read_credentials(stream: &mut TcpStream) -> Result<(String, String)> {
+    let version = stream.read_u8().await?;
+    if version != USER_PASS_VERSION {
+        return Err(anyhow!("Unsupported auth version: {version}"));
+    }
+    let ulen = stream.read_u8().await? as usize;
+    let mut username = vec![0u8; ulen];
+    stream.read_exact(&mut username).await?;
+    let plen = stream.read_u8().await? as usize;
+    let mut password = vec![0u8; plen];
+    stream.read_exact(&mut password).await?;
+    Ok((
+        String::from_utf8_lossy(&username).into_owned(),
+        String::from_utf8_lossy(&password).into_owned(),
+    ))
+}
+
+/// Serves one SOCKS5 client, resolving its egress interface from the negotiated credentials.
+pub async fn serve(mut stream: TcpStream, peer: SocketAddr, router: &Router) -> Result<()> {
+    let version = stream.read_u8().await?;
+    if version != SOCKS_VERSION {
+        return Err(anyhow!("Unsupported SOCKS version: {version}"));
+    }
+    let nmethods = stream.read_u8().await? as usize;
+    let mut methods = vec![0u8; nmethods];
+    stream.read_exact(&mut methods).await?;
+
+    // Selection needs the username, so ask for credentials whenever the client can send them.
+    let preferred = if router.wants_username() {
+        [METHOD_USER_PASS, METHOD_NO_AUTH]
+    } else {
+        [METHOD_NO_AUTH, METHOD_USER_PASS]
+    };
+    let Some(method) = preferred.into_iter().find(|m| methods.contains(m)) else {
+        stream
+            .write_all(&[SOCKS_VERSION, METHOD_NOT_ACCEPTABLE])
+            .await?;
+        return Err(anyhow!("No acceptable authentication method"));
+    };
+    stream.write_all(&[SOCKS_VERSION, method]).await?;
+
+    let resolved = if method == METHOD_USER_PASS {
+        let (username, _password) = read_credentials(&mut stream).await?;
+        let request = ClientRequest {
+            peer,
+            username: Some(&username),
+            headers: &[],
+        };
+        let resolved = router.resolve(&request).await;
+        let status = if resolved.is_ok() {
+            AUTH_SUCCEEDED
+        } else {
+            AUTH_FAILED
+        };
+        stream.write_all(&[USER_PASS_VERSION, status]).await?;
+        resolved
+    } else {
+        let request = ClientRequest {
+            peer,
+            username: None,
+            headers: &[],
+        };
+        router.resolve(&request).await
+    };
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    let [version, cmd, _rsv, atyp] = header;
+    if version != SOCKS_VERSION {
+        return Err(anyhow!("Unsupported SOCKS version: {version}"));
+    }
+    let target = match read_target_addr(&mut stream, atyp).await {
+        Ok(target) => target,
+        Err(e) => {
+            send_reply(
+                &mut stream,
+                REP_ADDRESS_TYPE_NOT_SUPPORTED,
+                unspecified_addr(),
+            )
+            .await?;
+            return Err(e);
+        }
+    };
+    let interface = match resolved {
+        Ok(interface) => interface,
+        Err(e) => {
+            send_reply(&mut stream, REP_NETWORK_UNREACHABLE, unspecified_addr()).await?;
+            return Err(e);
+        }
+    };
+    trace!(
+        "{}: {} requested cmd {} to {}",
+        interface, peer, cmd, target
+    );
+
+    let manager = router.manager();
+    let _connection = manager.connection_opened(&interface).await;
+    match cmd {
+        CMD_CONNECT => handle_connect(stream, &interface, target).await,
+        CMD_BIND => handle_bind(stream, manager, &interface).await,
+        CMD_UDP_ASSOCIATE => handle_udp_associate(stream, peer, &interface).await,
+        _ => {
+            send_reply(&mut stream, REP_COMMAND_NOT_SUPPORTED, unspecified_addr()).await?;
+            Err(anyhow!("Unsupported command: {cmd}"))
+        }
+    }
+}
+
+async fn handle_connect(mut stream: TcpStream, interface: &str, target: TargetAddr) -> Result<()> {
+    let result = match target.resolve().await {
+        Ok(addr) => connect_via(interface, addr).await,
+        Err(e) => Err(e),
+    };
+    let mut remote = match result {
+        Ok(remote) => remote,
+        Err(e) => {
+            send_reply(&mut stream, reply_code(&e), unspecified_addr()).await?;
+            return Err(anyhow!("Failed to connect to {}: {}", target, e));
+        }
+    };
+
+    let bound = remote.local_addr().unwrap_or_else(|_| unspecified_addr());
+    send_reply(&mut stream, REP_SUCCEEDED, bound).await?;
+
+    let (sent, received) = tokio::io::copy_bidirectional(&mut stream, &mut remote).await?;
+    trace!(
+        "{}: {} closed, {} bytes sent, {} bytes received",
+        interface, target, sent, received
+    );
+    Ok(())
+}
+
+async fn handle_bind(mut stream: TcpStream, manager: &PPPoEManager, interface: &str) -> Result<()> {
+    let socket = TcpSocket::new_v4()?;
+    socket.bind_device(Some(interface.as_bytes()))?;
+    socket.bind(unspecified_addr())?;
+    let listener = socket.listen(1)?;
+
+    // Report the address peers should dial: the session IP when known, else ours.
+    let local_ip = match manager.local_ip(interface).await {
+        Some(ip) => ip,
+        None => stream.local_addr()?.ip(),
+    };
+    let bound = SocketAddr::new(local_ip, listener.local_addr()?.port());
+    send_reply(&mut stream, REP_SUCCEEDED, bound).await?;
+
+    let (mut remote, remote_addr) = match timeout(BIND_ACCEPT_TIMEOUT, listener.accept()).await {
+        Ok(Ok(conn)) => conn,
+        Ok(Err(e)) => {
+            send_reply(&mut stream, reply_code(&e), unspecified_addr()).await?;
+            return Err(e.into());
+        }
+        Err(_) => {
+            send_reply(&mut stream, REP_HOST_UNREACHABLE, unspecified_addr()).await?;
+            return Err(anyhow!("Timed out waiting for inbound BIND connection"));
+        }
+    };
+    send_reply(&mut stream, REP_SUCCEEDED, remote_addr).await?;
+
+    tokio::io::copy_bidirectional(&mut stream, &mut remote).await?;
+    Ok(())
+}
+
+async fn handle_udp_associate(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    interface: &str,
+) -> Result<()> {
+    let client_socket = UdpSocket::bind((stream.local_addr()?.ip(), 0)).await?;
+    let remote_socket = UdpSocket::bind(unspecified_addr()).await?;
+    remote_socket.bind_device(Some(interface.as_bytes()))?;
+
+    send_reply(&mut stream, REP_SUCCEEDED, client_socket.local_addr()?).await?;
+
+    let mut client_addr: Option<SocketAddr> = None;
+    let mut client_buf = vec![0u8; UDP_BUFFER_SIZE];
+    let mut remote_buf = vec![0u8; UDP_BUFFER_SIZE];
+    let mut control_buf = [0u8; 1];
+
+    // The association lives as long as the TCP control connection.
+    loop {
+        tokio::select! {
+            result = stream.read(&mut control_buf) => {
+                if matches!(result, Ok(0) | Err(_)) {
+                    break;
+                }
+            }
+            result = client_socket.recv_from(&mut client_buf) => {
+                let (n, src) = result?;
+                if src.ip() != peer.ip() {
+                    continue;
+                }
+                client_addr = Some(src);
+                let Some((target, offset)) = parse_udp_header(&client_buf[..n]) else {
+                    continue;
+                };
+                match target.resolve().await {
+                    Ok(addr) => {
+                        if let Err(e) = remote_socket.send_to(&client_buf[offset..n], addr).await {
+                            debug!("{}: UDP send to {} failed: {}", interface, target, e);
+                        }
+                    }
+                    Err(e) => debug!("{}: Failed to resolve {}: {}", interface, target, e),
+                }
+            }
+            result = remote_socket.recv_from(&mut remote_buf) => {
+                let (n, src) = result?;
+                let Some(client) = client_addr else {
+                    continue;
+                };
+                let mut packet = vec![0x00, 0x00, 0x00];
+                write_socket_addr(&mut packet, src);
+                packet.extend_from_slice(&remote_buf[..n]);
+                client_socket.send_to(&packet, client).await?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,165 @@
+use anyhow::{Result, anyhow};
+use std::collections::{BTreeMap, HashMap};
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+use crate::core::config::StickyPolicy;
+use crate::pppoe::manager::ConnectionInfo;
+
+#[derive(Debug)]
+struct StickyEntry {
+    interface: String,
+    local_ip: Option<String>,
+    expires_at: Instant,
+}
+
+/// What a client key is mapped to right now.
+#[derive(Debug, PartialEq, Eq)]
+pub enum StickyLookup {
+    /// The mapped session, to be used.
+    Mapped(String),
+    /// The mapped session is down for now, e.g. rotating or failing health checks; pick
+    /// another for this request without remapping the key.
+    Unavailable,
+    /// No mapping, or one that was just dropped; pick a session and map the key to it.
+    Unmapped,
+}
+
+/// Client key to session mappings that expire after a period of inactivity.
+#[derive(Debug)]
+pub struct StickyTable {
+    ttl: Duration,
+    policy: StickyPolicy,
+    entries: Mutex<HashMap<String, StickyEntry>>,
+}
+
+impl StickyTable {
+    pub fn new(ttl: Duration, policy: StickyPolicy) -> Self {
+        Self {
+            ttl,
+            policy,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the session mapped to `key`, refreshing its TTL.
+    ///
+    /// Under `StickyPolicy::Invalidate` a mapping is dropped once its session is gone or
+    /// up with a new IP; while the session is only down the mapping stays for when it is
+    /// back. Under `StickyPolicy::Keep` an unavailable session is an error rather than a
+    /// remap, so the client keeps its egress once the session is back.
+    pub async fn lookup(
+        &self,
+        key: &str,
+        stats: &BTreeMap<String, ConnectionInfo>,
+    ) -> Result<StickyLookup> {
+        let mut entries = self.entries.lock().await;
+        let now = Instant::now();
+        let Some(entry) = entries.get_mut(key) else {
+            return Ok(StickyLookup::Unmapped);
+        };
+        if entry.expires_at <= now {
+            entries.remove(key);
+            return Ok(StickyLookup::Unmapped);
+        }
+
+        let Some(info) = stats.get(&entry.interface) else {
+            entries.remove(key);
+            return Ok(StickyLookup::Unmapped);
+        };
+        let ip_changed = info
+            .local_ip
+            .as_ref()
+            .is_some_and(|ip| Some(ip) != entry.local_ip.as_ref());
+
+        match self.policy {
+            StickyPolicy::Invalidate if ip_changed => {
+                entries.remove(key);
+                Ok(StickyLookup::Unmapped)
+            }
+            StickyPolicy::Invalidate if !info.is_available() => Ok(StickyLookup::Unavailable),
+            StickyPolicy::Keep if !info.is_available() => {
+                Err(anyhow!("Sticky session {} is unavailable", entry.interface))
+            }
+            _ => {
+                entry.expires_at = now + self.ttl;
+                Ok(StickyLookup::Mapped(entry.interface.clone()))
+            }
+        }
+    }
+
+    pub async fn insert(&self, key: String, interface: String, local_ip: Option<String>) {
+        let mut entries = self.entries.lock().await;
+        let now = Instant::now();
+        entries.retain(|_, entry| entry.expires_at > now);
+        entries.insert(
+            key,
+            StickyEntry {
+                interface,
+                local_ip,
+                expires_at: now + self.ttl,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(local_ip: Option<&str>) -> BTreeMap<String, ConnectionInfo> {
+        let info = ConnectionInfo {
+            local_ip: local_ip.map(str::to_string),
+            rotating: local_ip.is_none(),
+            ..Default::default()
+        };
+        BTreeMap::from([("ppp0".to_string(), info)])
+    }
+
+    #[tokio::test]
+    async fn invalidate_keeps_mapping_while_session_is_down() {
+        let table = StickyTable::new(Duration::from_secs(60), StickyPolicy::Invalidate);
+        let ip = Some("100.64.0.2".to_string());
+        table
+            .insert("user:a".to_string(), "ppp0".to_string(), ip)
+            .await;
+
+        let rotating = stats(None);
+        assert_eq!(
+            table.lookup("user:a", &rotating).await.unwrap(),
+            StickyLookup::Unavailable
+        );
+        let same_ip = stats(Some("100.64.0.2"));
+        assert_eq!(
+            table.lookup("user:a", &same_ip).await.unwrap(),
+            StickyLookup::Mapped("ppp0".to_string())
+        );
+        let new_ip = stats(Some("100.64.0.3"));
+        assert_eq!(
+            table.lookup("user:a", &new_ip).await.unwrap(),
+            StickyLookup::Unmapped
+        );
+        assert_eq!(
+            table.lookup("user:a", &same_ip).await.unwrap(),
+            StickyLookup::Unmapped
+        );
+    }
+
+    #[tokio::test]
+    async fn keep_fails_while_session_is_down() {
+        let table = StickyTable::new(Duration::from_secs(60), StickyPolicy::Keep);
+        let ip = Some("100.64.0.2".to_string());
+        table
+            .insert("user:a".to_string(), "ppp0".to_string(), ip)
+            .await;
+
+        assert!(table.lookup("user:a", &stats(None)).await.is_err());
+        assert_eq!(
+            table
+                .lookup("user:a", &stats(Some("100.64.0.3")))
+                .await
+                .unwrap(),
+            StickyLookup::Mapped("ppp0".to_string())
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use anyhow::{Result, anyhow};
+use log::{error, info, warn};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::Duration;
+
+use crate::core::config::{AppConfig, MAX_SESSION_COUNT};
+use crate::core::logger;
+use crate::network::allocator::RouteAllocator;
+use crate::network::nft;
+use crate::network::route::{init_route, teardown_route};
+use crate::pppoe::manager::PPPoEManager;
+use crate::proxy::listener::ProxyListeners;
+use crate::proxy::server::ProxyServer;
+
+/// How long shutdown waits for proxied connections before taking the sessions down.
+const PROXY_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The running services and the config they were started with.
+pub struct Runtime {
+    /// What is actually applied; settings that need a restart keep their startup value.
+    config: Mutex<AppConfig>,
+    manager: Arc<PPPoEManager>,
+    routes: Arc<RouteAllocator>,
+    proxy: Arc<Mutex<ProxyServer>>,
+    listeners: Arc<ProxyListeners>,
+}
+
+impl Runtime {
+    pub fn new(
+        config: AppConfig,
+        manager: Arc<PPPoEManager>,
+        routes: Arc<RouteAllocator>,
+        proxy: Arc<Mutex<ProxyServer>>,
+        listeners: Arc<ProxyListeners>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            config: Mutex::new(config),
+            manager,
+            routes,
+            proxy,
+            listeners,
+        })
+    }
+
+    pub fn manager(&self) -> &Arc<PPPoEManager> {
+        &self.manager
+    }
+
+    /// Reads the config file again and applies what changed. Returns a description of
+    /// every change applied.
+    pub async fn reload(&self) -> Result<Vec<String>> {
+        let new = AppConfig::reload()?;
+        let changes = self.apply(new).await?;
+        if changes.is_empty() {
+            info!("Configuration reloaded, nothing changed");
+        }
+        for change in &changes {
+            info!("Configuration reloaded: {}", change);
+        }
+        Ok(changes)
+    }
+
+    /// Sets how many sessions `account` dials, starting or stopping sessions along with
+    /// their routes, firewall rules and proxy listeners. `account` may be left out when
+    /// there is only one.
+    ///
+    /// The new count lasts until the next reload, which goes back to the config file.
+    pub async fn scale(&self, account: Option<&str>, sessions: u16) -> Result<Vec<String>> {
+        let mut current = self.config.lock().await;
+        let mut new = current.clone();
+        let target = match account {
+            Some(name) => new
+                .accounts
+                .iter_mut()
+                .find(|a| a.name == name)
+                .ok_or_else(|| anyhow!("No account named {}", name))?,
+            None if new.accounts.len() == 1 => &mut new.accounts[0],
+            None => {
+                return Err(anyhow!(
+                    "There are {} accounts, name the one to scale",
+                    new.accounts.len()
+                ));
+            }
+        };
+        let name = target.name.clone();
+        target.sessions = sessions;
+
+        let total: u32 = new.accounts.iter().map(|a| u32::from(a.sessions)).sum();
+        if total > u32::from(MAX_SESSION_COUNT) {
+            return Err(anyhow!(
+                "{} sessions over all accounts would exceed {}",
+                total,
+                MAX_SESSION_COUNT
+            ));
+        }
+        new.session_count = total as u16;
+        new.check_ports()?;
+
+        info!("Scaling {} to {} session(s)", name, sessions);
+        let changes = self.apply_to(&mut current, new).await?;
+        for change in &changes {
+            info!("Scaled: {}", change);
+        }
+        Ok(changes)
+    }
+
+    /// Applies `new` without restarting sessions that exist in both configs.
+    async fn apply(&self, new: AppConfig) -> Result<Vec<String>> {
+        let mut current = self.config.lock().await;
+        self.apply_to(&mut current, new).await
+    }
+
+    async fn apply_to(&self, current: &mut AppConfig, new: AppConfig) -> Result<Vec<String>> {
+        let mut changes = Vec::new();
+
+        // Firewall and routes go first, being all that can fail: an error then leaves the
+        // rest untouched, and what did succeed is recorded so a retry picks up from there.
+        // Growing opens the firewall before the routes need it, shrinking closes it after.
+        let (from, to) = (current.session_count, new.session_count);
+        if to > from {
+            nft::apply(&nft::ruleset(&current.wan_interface, to)).await?;
+        }
+        if to > from || new.gateway != current.gateway {
+            init_route(&new.gateway, &current.wan_interface, to, &self.routes).await?;
+            current.gateway = new.gateway.clone();
+        }
+        if to < from {
+            nft::apply(&nft::ruleset(&current.wan_interface, to)).await?;
+        }
+
+        if new.log_filter != current.log_filter {
+            logger::set_filter(&new.log_filter);
+            changes.push(format!("log filter set to {}", new.log_filter));
+            current.log_filter = new.log_filter.clone();
+        }
+
+        for restarted in self.manager.reload(new.ip_rotation.clone()).await {
+            changes.push(format!("{} restarted", restarted));
+        }
+        current.ip_rotation = new.ip_rotation.clone();
+
+        if ProxyServer::reconfigure(Arc::clone(&self.proxy), to, new.logger_level.clone()).await {
+            changes.push("proxy service restarted".to_string());
+        }
+        current.logger_level = new.logger_level.clone();
+
+        if new.sessions() != current.sessions() {
+            let clients = self.manager.resize(&new.sessions()).await;
+            self.manager.start_schedules().await;
+            self.listeners.resize(from, to).await;
+            for i in to..from {
+                self.routes.release(&format!("tun{}", i + 1)).await;
+            }
+            current.accounts = new.accounts.clone();
+            current.session_uplinks = new.session_uplinks.clone();
+            current.session_count = to;
+            if !clients.started.is_empty() {
+                changes.push(format!("started {}", clients.started.join(", ")));
+            }
+            if !clients.stopped.is_empty() {
+                changes.push(format!("stopped {}", clients.stopped.join(", ")));
+            }
+            if !clients.switched.is_empty() {
+                changes.push(format!(
+                    "reconnected {} with new settings",
+                    clients.switched.join(", ")
+                ));
+            }
+        }
+
+        let mut needs_restart = Vec::new();
+        if new.wan_interface != current.wan_interface {
+            needs_restart.push("WAN_INTERFACE");
+        }
+        if new.proxy != current.proxy {
+            needs_restart.push("proxy listeners");
+        }
+        if new.routing != current.routing {
+            needs_restart.push("routing");
+        }
+        if new.api != current.api {
+            needs_restart.push("API");
+        }
+        if new.history_path != current.history_path {
+            needs_restart.push("IP_HISTORY_PATH");
+        }
+        if new.session_log != current.session_log {
+            needs_restart.push("session logs");
+        }
+        if new.discord_token != current.discord_token
+            || new.discord_guild_id != current.discord_guild_id
+            || new.discord_alert_channel != current.discord_alert_channel
+        {
+            needs_restart.push("Discord bot");
+        }
+        if !needs_restart.is_empty() {
+            warn!(
+                "Changes to {} take effect after a restart",
+                needs_restart.join(", ")
+            );
+        }
+        Ok(changes)
+    }
+
+    /// Drains the proxies, disconnects the sessions and removes routes and firewall rules.
+    pub async fn shutdown(&self) {
+        info!("Stopping services...");
+        self.listeners.stop().await;
+        self.listeners.drain(PROXY_DRAIN_TIMEOUT).await;
+        self.manager.shutdown(Duration::from_secs(10)).await;
+        ProxyServer::stop(Arc::clone(&self.proxy)).await;
+
+        info!("Removing routes and firewall rules...");
+        teardown_route(&self.routes).await;
+        if let Err(e) = nft::teardown().await {
+            error!("Failed to remove nftables rules: {}", e);
+        }
+    }
+}